@@ -0,0 +1,365 @@
+//! Forwards every NetMon record to the host's log collector, so cleanup
+//! runs show up next to the rest of the admin activity on the host.
+//!
+//! Two transports over a local unix datagram socket:
+//! - `syslog`: RFC 5424 messages with the record in structured data,
+//!   usually on `/dev/log`.
+//! - `journald`: the native journal protocol (`KEY=value` fields) on
+//!   `/run/systemd/journal/socket`.
+//!
+//! The socket path is configurable, so any `UnixDatagram` bound to a temp
+//! path can stand in for the collector.
+//!
+//! Messages are sent from a thread of the sink's own, through a bounded
+//! queue: a stalled collector costs dropped audit records, counted in the
+//! run's stats, but never holds up NetMon. Dropping the sink sends what is
+//! still queued.
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::net_mon::{Action, ActionRecord};
+use crate::session::HostInfo;
+
+const APP_NAME: &str = "cleanCleanCleaner";
+/// Structured-data id. 32473 is the private enterprise number reserved
+/// for examples (RFC 5612); there is no registered one for this tool.
+const SD_ID: &str = "cleanup@32473";
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+/// Messages waiting for the collector before new ones are dropped.
+const QUEUE_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditConfig {
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: PathBuf,
+        /// Syslog facility name, e.g. `authpriv`, `daemon`, `local0`.
+        #[serde(default = "default_facility")]
+        facility: String,
+    },
+    Journald {
+        #[serde(default = "default_journald_socket")]
+        socket: PathBuf,
+    },
+}
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+fn default_journald_socket() -> PathBuf {
+    PathBuf::from("/run/systemd/journal/socket")
+}
+
+fn default_facility() -> String {
+    "authpriv".to_string()
+}
+
+fn facility_code(name: &str) -> Result<u8> {
+    let code = match name {
+        "kern" => 0,
+        "user" => 1,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "authpriv" => 10,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => bail!("Unknown syslog facility: {}", name),
+    };
+    Ok(code)
+}
+
+enum Format {
+    Syslog { facility: u8 },
+    Journald,
+}
+
+pub struct AuditSink {
+    /// Messages for the sender thread; `None` once the sink is dropped.
+    queue: Option<SyncSender<Vec<u8>>>,
+    sender: Option<JoinHandle<()>>,
+    /// Set on drop: a collector that stops answering then gets no more.
+    closing: Arc<AtomicBool>,
+    format: Format,
+    session_id: Uuid,
+    hostname: String,
+    pid: u32,
+}
+
+impl AuditSink {
+    pub fn connect(config: &AuditConfig, session_id: Uuid) -> Result<Self> {
+        let (path, format) = match config {
+            AuditConfig::Syslog { socket, facility } => (socket, Format::Syslog { facility: facility_code(facility)? }),
+            AuditConfig::Journald { socket } => (socket, Format::Journald),
+        };
+        let socket = UnixDatagram::unbound().context("Failed to create audit socket")?;
+        socket
+            .connect(path)
+            .with_context(|| format!("Failed to connect to audit socket: {}", path.display()))?;
+        socket.set_write_timeout(Some(SEND_TIMEOUT)).context("Failed to set audit socket timeout")?;
+
+        let (queue, messages) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LEN);
+        let closing = Arc::new(AtomicBool::new(false));
+        let stop = closing.clone();
+        // Ends once the sink, and with it the queue's sender, is dropped.
+        let sender = std::thread::Builder::new()
+            .name("audit".to_string())
+            .spawn(move || {
+                for message in messages {
+                    if let Err(e) = socket.send(&message) {
+                        log::error!("Failed to send audit record: {}", e);
+                        if stop.load(Ordering::SeqCst) {
+                            log::error!("Dropping the audit records still queued");
+                            break;
+                        }
+                    }
+                }
+            })
+            .context("Failed to start audit thread")?;
+        Ok(Self {
+            queue: Some(queue),
+            sender: Some(sender),
+            closing,
+            format,
+            session_id,
+            hostname: HostInfo::current().hostname,
+            pid: std::process::id(),
+        })
+    }
+
+    /// Queues `record` for the collector. Does not block; fails if the
+    /// queue is full.
+    pub fn send(&self, record: &ActionRecord) -> Result<()> {
+        let message = match self.format {
+            Format::Syslog { facility } => self.syslog_message(facility, record),
+            Format::Journald => self.journald_message(record),
+        };
+        let queue = self.queue.as_ref().context("Audit sink is closed")?;
+        match queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => bail!("Audit collector is not keeping up, record dropped"),
+            Err(TrySendError::Disconnected(_)) => bail!("Audit thread has stopped"),
+        }
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [SD] MSG`
+    fn syslog_message(&self, facility: u8, record: &ActionRecord) -> Vec<u8> {
+        let pri = facility * 8 + severity(&record.action);
+        let mut params = vec![
+            ("session", self.session_id.to_string()),
+            ("kind", record.action.kind().to_string()),
+        ];
+        if let Some(task) = &record.task {
+            params.push(("task", task.clone()));
+        }
+        if let Some(user) = &record.user {
+            params.push(("user", user.clone()));
+        }
+        if let Some(target) = record_target(&record.action) {
+            params.push(("target", target.to_string()));
+        }
+        let sd: String = params
+            .iter()
+            .map(|(name, value)| format!(" {}=\"{}\"", name, sd_escape(value)))
+            .collect();
+        format!(
+            "<{}>1 {} {} {} {} {} [{}{}] {}",
+            pri,
+            record.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            self.hostname,
+            APP_NAME,
+            self.pid,
+            record.action.kind(),
+            SD_ID,
+            sd,
+            record.action.detail(),
+        )
+        .into_bytes()
+    }
+
+    fn journald_message(&self, record: &ActionRecord) -> Vec<u8> {
+        let mut out = Vec::new();
+        let message = format!("{}: {}", record.action.kind(), record.action.detail());
+        journald_field(&mut out, "MESSAGE", &message);
+        journald_field(&mut out, "PRIORITY", &severity(&record.action).to_string());
+        journald_field(&mut out, "SYSLOG_IDENTIFIER", APP_NAME);
+        journald_field(&mut out, "CLEANUP_SESSION", &self.session_id.to_string());
+        journald_field(&mut out, "CLEANUP_KIND", record.action.kind());
+        journald_field(&mut out, "CLEANUP_TIMESTAMP", &record.timestamp.to_rfc3339());
+        if let Some(task) = &record.task {
+            journald_field(&mut out, "CLEANUP_TASK", task);
+        }
+        if let Some(user) = &record.user {
+            journald_field(&mut out, "CLEANUP_USER", user);
+        }
+        if let Some(target) = record_target(&record.action) {
+            journald_field(&mut out, "CLEANUP_TARGET", target);
+        }
+        out
+    }
+}
+
+impl Drop for AuditSink {
+    /// Waits for the thread to send what is still queued.
+    fn drop(&mut self) {
+        self.closing.store(true, Ordering::SeqCst);
+        drop(self.queue.take());
+        if let Some(sender) = self.sender.take() {
+            if sender.join().is_err() {
+                log::error!("Audit thread panicked");
+            }
+        }
+    }
+}
+
+/// Syslog severity: 3 (err) for failures, 5 (notice) for everything else.
+fn severity(action: &Action) -> u8 {
+    if action.error_kind().is_some() { 3 } else { 5 }
+}
+
+fn record_target(action: &Action) -> Option<&str> {
+    match action {
+        Action::TargetFailed(target, _) | Action::SpaceFreed(target, _) | Action::FileInUse(target, ..) => Some(target),
+        _ => action.completed_target(),
+    }
+}
+
+/// Escapes `"`, `\` and `]` in a structured-data parameter value.
+fn sd_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Appends one field. Values with a newline use the length-prefixed
+/// binary form of the journal protocol.
+fn journald_field(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        out.push(b'\n');
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        out.push(b'=');
+    }
+    out.extend_from_slice(value.as_bytes());
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use chrono::Utc;
+
+    /// A bound datagram socket standing in for the collector.
+    fn collector(dir: &Path) -> (UnixDatagram, PathBuf) {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("collector.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (socket, path)
+    }
+
+    fn record(action: Action) -> ActionRecord {
+        ActionRecord { timestamp: Utc::now(), task: Some("tmp".to_string()), user: Some("alice".to_string()), action }
+    }
+
+    fn receive(socket: &UnixDatagram) -> Vec<u8> {
+        let mut buf = vec![0; 64 * 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn syslog_framing() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", Uuid::new_v4()));
+        let (socket, path) = collector(&dir);
+        let session_id = Uuid::new_v4();
+        let config = AuditConfig::Syslog { socket: path, facility: "local0".to_string() };
+        let sink = AuditSink::connect(&config, session_id).unwrap();
+
+        sink.send(&record(Action::TargetFailed(r#"/tmp/a"b]c\d"#.to_string(), "Permission denied".to_string()))).unwrap();
+        let message = String::from_utf8(receive(&socket)).unwrap();
+
+        // local0 (16) * 8 + err (3)
+        assert!(message.starts_with("<131>1 "), "{}", message);
+        let header = format!(" {} {} TargetFailed [cleanup@32473 session=\"{}\" kind=\"TargetFailed\" task=\"tmp\" user=\"alice\"", APP_NAME, std::process::id(), session_id);
+        assert!(message.contains(&header), "{}", message);
+        assert!(message.contains(r#" target="/tmp/a\"b\]c\\d"] "#), "{}", message);
+        assert!(message.ends_with(r#"] /tmp/a"b]c\d: Permission denied"#), "{}", message);
+
+        sink.send(&record(Action::FileDeleted("/tmp/x".to_string()))).unwrap();
+        let message = String::from_utf8(receive(&socket)).unwrap();
+        // notice (5)
+        assert!(message.starts_with("<133>1 "), "{}", message);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sd_escape_escapes_quote_backslash_and_bracket() {
+        assert_eq!(sd_escape(r#"a"b\c]d[e"#), r#"a\"b\\c\]d[e"#);
+    }
+
+    #[test]
+    fn journald_fields() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", Uuid::new_v4()));
+        let (socket, path) = collector(&dir);
+        let sink = AuditSink::connect(&AuditConfig::Journald { socket: path }, Uuid::new_v4()).unwrap();
+
+        sink.send(&record(Action::Error("first line\nsecond line".to_string()))).unwrap();
+        let message = receive(&socket);
+
+        let value = b"Error: first line\nsecond line";
+        let mut binary = b"MESSAGE\n".to_vec();
+        binary.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        binary.extend_from_slice(value);
+        binary.push(b'\n');
+        assert!(message.starts_with(&binary));
+
+        let text = String::from_utf8_lossy(&message);
+        assert!(text.contains("\nPRIORITY=3\n"));
+        assert!(text.contains("\nCLEANUP_KIND=Error\n"));
+        assert!(text.contains("\nCLEANUP_TASK=tmp\n"));
+        assert!(text.contains("\nCLEANUP_USER=alice\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dropping_the_sink_sends_what_is_queued() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", Uuid::new_v4()));
+        let (socket, path) = collector(&dir);
+        let sink = AuditSink::connect(&AuditConfig::Journald { socket: path }, Uuid::new_v4()).unwrap();
+        for i in 0..50 {
+            sink.send(&record(Action::FileDeleted(format!("/tmp/{}", i)))).unwrap();
+        }
+        // The collector's socket holds only a few datagrams: read while
+        // the sink drains its queue.
+        let reader = std::thread::spawn(move || (0..50).filter(|_| !receive(&socket).is_empty()).count());
+        drop(sink);
+        assert_eq!(reader.join().unwrap(), 50);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
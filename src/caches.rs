@@ -0,0 +1,253 @@
+//! Reclaimable caches of package managers and build tools.
+//!
+//! Each cache is a set of roots; the entries found at a fixed depth below a
+//! root are what gets removed, one at a time, so an age filter can keep the
+//! recently used ones. An entry's age is that of the newest file in it.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use log::info;
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+use crate::utils::{remove_and_record, tree_stats};
+
+/// How deep to look for `target` dirs under `CacheTask::target_roots`.
+const TARGET_SEARCH_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheKind {
+    /// `~/.cargo` registry and git checkouts, plus build `target` dirs.
+    Cargo,
+    Pip,
+    Npm,
+    Yarn,
+    /// Downloaded packages in `/var/cache/apt/archives`.
+    Apt,
+    Dnf,
+    /// Freedesktop thumbnail cache.
+    Thumbnails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheTask {
+    pub cache: CacheKind,
+    /// Only remove entries untouched for this many days.
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+    /// Home directories to clean; defaults to `$HOME`. Unused by the
+    /// system caches (apt, dnf).
+    #[serde(default)]
+    pub homes: Vec<PathBuf>,
+    /// Where to look for cargo `target` dirs (marked by `CACHEDIR.TAG`).
+    #[serde(default)]
+    pub target_roots: Vec<PathBuf>,
+}
+
+/// Entries `depth` levels below `path`; with `extension`, only files
+/// with that extension.
+struct CacheRoot {
+    path: PathBuf,
+    depth: usize,
+    extension: Option<&'static str>,
+}
+
+impl CacheRoot {
+    fn new(path: PathBuf, depth: usize) -> Self {
+        Self { path, depth, extension: None }
+    }
+
+    fn files(path: PathBuf, depth: usize, extension: &'static str) -> Self {
+        Self { path, depth, extension: Some(extension) }
+    }
+}
+
+impl CacheTask {
+    fn homes(&self) -> Vec<PathBuf> {
+        if !self.homes.is_empty() {
+            return self.homes.clone();
+        }
+        env::var_os("HOME").map(PathBuf::from).into_iter().collect()
+    }
+
+    fn roots(&self) -> Vec<CacheRoot> {
+        let mut roots = Vec::new();
+        match self.cache {
+            CacheKind::Apt => roots.push(CacheRoot::files("/var/cache/apt/archives".into(), 1, "deb")),
+            CacheKind::Dnf => roots.push(CacheRoot::new("/var/cache/dnf".into(), 1)),
+            _ => {
+                for home in self.homes() {
+                    roots.extend(self.home_roots(&home));
+                }
+            }
+        }
+        roots
+    }
+
+    fn home_roots(&self, home: &Path) -> Vec<CacheRoot> {
+        match self.cache {
+            CacheKind::Cargo => vec![
+                CacheRoot::files(home.join(".cargo/registry/cache"), 2, "crate"),
+                CacheRoot::new(home.join(".cargo/registry/src"), 2),
+                CacheRoot::new(home.join(".cargo/git/checkouts"), 1),
+            ],
+            CacheKind::Pip => vec![
+                CacheRoot::new(home.join(".cache/pip/http"), 1),
+                CacheRoot::new(home.join(".cache/pip/http-v2"), 1),
+                CacheRoot::new(home.join(".cache/pip/wheels"), 1),
+            ],
+            CacheKind::Npm => vec![CacheRoot::new(home.join(".npm/_cacache"), 1)],
+            CacheKind::Yarn => vec![
+                CacheRoot::new(home.join(".cache/yarn"), 2),
+                CacheRoot::new(home.join(".yarn/berry/cache"), 1),
+            ],
+            CacheKind::Thumbnails => vec![CacheRoot::new(home.join(".cache/thumbnails"), 2)],
+            CacheKind::Apt | CacheKind::Dnf => Vec::new(),
+        }
+    }
+
+    /// Entries old enough to remove, with their size. Blocking.
+    pub fn candidates(&self) -> Vec<(PathBuf, u64)> {
+        let cutoff = self
+            .older_than_days
+            .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days * 86_400)));
+
+        let mut entries = Vec::new();
+        for root in self.roots() {
+            collect_entries(&root.path, root.depth, root.extension, &mut entries);
+        }
+        if self.cache == CacheKind::Cargo {
+            for root in &self.target_roots {
+                find_target_dirs(root, TARGET_SEARCH_DEPTH, &mut entries);
+            }
+        }
+
+        entries
+            .into_iter()
+            .filter_map(|path| {
+                let (size, newest) = tree_stats(&path);
+                let old_enough = match (cutoff, newest) {
+                    (Some(cutoff), Some(newest)) => newest < cutoff,
+                    (Some(_), None) => false,
+                    (None, _) => true,
+                };
+                old_enough.then_some((path, size))
+            })
+            .collect()
+    }
+}
+
+fn collect_entries(dir: &Path, depth: usize, extension: Option<&str>, out: &mut Vec<PathBuf>) {
+    let Ok(read_dir) = fs::read_dir(dir) else { return };
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        if depth > 1 {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                collect_entries(&path, depth - 1, extension, out);
+            }
+        } else {
+            let wanted = match extension {
+                Some(ext) => path.extension().is_some_and(|e| e == ext),
+                None => true,
+            };
+            if wanted {
+                out.push(path);
+            }
+        }
+    }
+}
+
+fn find_target_dirs(dir: &Path, depth: usize, out: &mut Vec<PathBuf>) {
+    let Ok(read_dir) = fs::read_dir(dir) else { return };
+    for entry in read_dir.flatten() {
+        if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_name() == "target" && path.join("CACHEDIR.TAG").is_file() {
+            out.push(path);
+        } else if depth > 1 && !entry.file_name().to_string_lossy().starts_with('.') {
+            find_target_dirs(&path, depth - 1, out);
+        }
+    }
+}
+
+/// Removes (or, with `dry_run`, sizes up) the entries of one cache.
+pub async fn clean_cache(net_mon: &NetMon, resume: &ResumeState, task: &CacheTask, dry_run: bool) -> Result<()> {
+    info!("Cleaning {:?} cache...", task.cache);
+    let scan = task.clone();
+    let candidates = tokio::task::spawn_blocking(move || scan.candidates()).await?;
+    info!("{} {:?} cache entries eligible", candidates.len(), task.cache);
+
+    for (path, size) in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+
+        remove_and_record(net_mon, &path, size).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// Sets the mtime of `path`, a file or dir, to `days` ago.
+    fn age(path: &Path, days: u64) {
+        let time = SystemTime::now() - Duration::from_secs(days * 86_400);
+        fs::File::open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    fn names(candidates: &[(PathBuf, u64)], home: &Path) -> Vec<String> {
+        let mut names: Vec<String> = candidates.iter().map(|(path, _)| path.strip_prefix(home).unwrap().display().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn candidates_are_filtered_by_their_newest_file() {
+        let home = std::env::temp_dir().join(format!("caches-test-{}", Uuid::new_v4()));
+        let http = home.join(".cache/pip/http");
+        for entry in ["old", "touched", "fresh"] {
+            fs::create_dir_all(http.join(entry)).unwrap();
+            fs::write(http.join(entry).join("blob"), b"12345").unwrap();
+        }
+        fs::write(http.join("touched/new"), b"1").unwrap();
+        for path in ["old/blob", "old", "touched/blob", "touched"] {
+            age(&http.join(path), 40);
+        }
+
+        let mut task = CacheTask { cache: CacheKind::Pip, older_than_days: Some(30), homes: vec![home.clone()], target_roots: Vec::new() };
+        let candidates = task.candidates();
+        assert_eq!(names(&candidates, &home), [".cache/pip/http/old"]);
+        assert_eq!(candidates[0].1, 5);
+
+        task.older_than_days = None;
+        assert_eq!(names(&task.candidates(), &home), [".cache/pip/http/fresh", ".cache/pip/http/old", ".cache/pip/http/touched"]);
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn file_roots_keep_only_their_extension() {
+        let home = std::env::temp_dir().join(format!("caches-test-{}", Uuid::new_v4()));
+        let index = home.join(".cargo/registry/cache/index.crates.io");
+        fs::create_dir_all(&index).unwrap();
+        fs::write(index.join("serde-1.0.0.crate"), b"crate").unwrap();
+        fs::write(index.join("notes.txt"), b"keep").unwrap();
+
+        let task = CacheTask { cache: CacheKind::Cargo, older_than_days: None, homes: vec![home.clone()], target_roots: Vec::new() };
+        assert_eq!(names(&task.candidates(), &home), [".cargo/registry/cache/index.crates.io/serde-1.0.0.crate"]);
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
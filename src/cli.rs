@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use uuid::Uuid;
+
+use crate::net_mon::DEFAULT_LOG_CAPACITY;
+use crate::report::ReportFormat;
+
+#[derive(Debug, Parser)]
+#[command(name = "cleanCleanCleaner", version, about = "Concurrent system cleanup tool")]
+pub struct Cli {
+    /// JSON config file (logging, ...).
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// log4rs YAML file; overrides the config file's logging section.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log4rs: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Run the cleanup tasks (the default when no subcommand is given).
+    Run(RunArgs),
+    /// Watch disk usage and run the plan whenever a mount fills up.
+    Daemon(DaemonArgs),
+    /// Measure disk usage under a path and suggest a plan.
+    Scan(ScanArgs),
+    /// Inspect saved reports.
+    Report {
+        #[command(subcommand)]
+        command: ReportCommand,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub struct RunArgs {
+    /// JSON plan file listing the tasks to run; defaults to the built-in
+    /// tasks.
+    #[arg(long, value_name = "PATH")]
+    pub plan: Option<PathBuf>,
+
+    /// Plan mode: size up what each task would remove, change nothing.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Run without the review prompt. Only allowed for a plan that had a
+    /// dry run before (same plan hash).
+    #[arg(short, long, conflicts_with = "dry_run")]
+    pub yes: bool,
+
+    /// Once the tasks that need elevated rights are done, run the rest as
+    /// this user.
+    #[arg(long, value_name = "USER")]
+    pub drop_privileges: Option<String>,
+
+    /// Continue an interrupted session, skipping targets its journal
+    /// marks as completed.
+    #[arg(long, value_name = "SESSION_ID")]
+    pub resume: Option<Uuid>,
+
+    /// Records NetMon keeps in memory; older ones are only in the
+    /// journal. 0 keeps everything.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LOG_CAPACITY)]
+    pub log_capacity: usize,
+
+    /// Report formats to write; repeat or comma-separate for several.
+    #[arg(long = "format", value_enum, value_delimiter = ',', default_value = "json")]
+    pub formats: Vec<ReportFormat>,
+}
+
+impl Default for RunArgs {
+    fn default() -> Self {
+        RunArgs::parse_from(["run"])
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct DaemonArgs {
+    /// JSON plan file; tasks run one at a time by dependencies, then
+    /// `priority`.
+    #[arg(long, value_name = "PATH")]
+    pub plan: Option<PathBuf>,
+
+    /// Mount to watch; repeat for several.
+    #[arg(long = "mount", value_name = "PATH", default_value = "/")]
+    pub mounts: Vec<PathBuf>,
+
+    /// Usage percentage that starts a cleanup cycle.
+    #[arg(long, value_name = "PERCENT", default_value_t = 90.0)]
+    pub high_water: f64,
+
+    /// Usage percentage at which a cycle stops running further tasks.
+    #[arg(long, value_name = "PERCENT", default_value_t = 80.0)]
+    pub low_water: f64,
+
+    /// Seconds between usage checks.
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub interval: u64,
+
+    /// Records NetMon keeps in memory per cycle. 0 keeps everything.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_LOG_CAPACITY)]
+    pub log_capacity: usize,
+
+    /// Report formats written for each cycle.
+    #[arg(long = "format", value_enum, value_delimiter = ',', default_value = "json")]
+    pub formats: Vec<ReportFormat>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ScanArgs {
+    /// Directory to scan; other mounts below it are skipped.
+    #[arg(default_value = "/")]
+    pub path: PathBuf,
+
+    /// How many of the largest directories to list.
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    pub top: usize,
+
+    /// Leave suggestions that would free less than this many MiB out of
+    /// the plan.
+    #[arg(long, value_name = "MIB", default_value_t = 50)]
+    pub min_size: u64,
+
+    /// Directories read at once.
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    pub jobs: usize,
+
+    /// Where to write the suggested plan.
+    #[arg(long, short, value_name = "PATH", default_value = "suggested_plan.json")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReportCommand {
+    /// Compare two JSON action reports: newly cleaned and newly failed
+    /// targets, and freed bytes per task.
+    Diff {
+        /// Older report, as a path or a session id.
+        old: String,
+        /// Newer report, as a path or a session id.
+        new: String,
+        /// Print the diff as JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+}
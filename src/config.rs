@@ -0,0 +1,25 @@
+//! Tool settings, loaded from the JSON file given with `--config`.
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+
+use crate::audit::AuditConfig;
+use crate::logging::LogConfig;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub logging: LogConfig,
+    /// Forward every action to syslog or journald.
+    pub audit: Option<AuditConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("Invalid config: {}", path.display()))
+    }
+}
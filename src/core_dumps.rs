@@ -0,0 +1,218 @@
+//! Core dumps and crash reports: systemd-coredump's store, apport's
+//! `/var/crash` and app-specific crash dirs.
+//!
+//! Dumps are grouped by the executable that crashed, taken from the file
+//! name where the format has it (`core.<comm>.<uid>.<boot id>.<pid>.<time>`
+//! for systemd, `<exe path>.<uid>.crash` for apport) and otherwise by the
+//! dir they are in, as app crash dirs hold one app's dumps. The newest
+//! `keep_per_executable` of each group are kept; then, if the kept dumps
+//! still exceed `max_total_bytes`, the oldest go first until they fit.
+//!
+//! Dumps hold process memory, secrets included, so `sanitize` overwrites
+//! each one as `secure_delete_file` does before removing it.
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+use crate::secure_delete_file;
+use crate::supervise::is_cancelled;
+use crate::utils::{format_bytes, remove_and_record};
+
+/// Sidecar files apport writes next to a `.crash` once it is reported.
+const APPORT_SIDECARS: [&str; 2] = ["upload", "uploaded"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreDumpsTask {
+    /// Dirs holding dumps; app-specific crash dirs can be added.
+    #[serde(default = "default_dirs")]
+    pub dirs: Vec<PathBuf>,
+    /// Newest dumps kept for each executable.
+    #[serde(default = "default_keep_per_executable")]
+    pub keep_per_executable: usize,
+    /// Total size the kept dumps may take up.
+    #[serde(default)]
+    pub max_total_bytes: Option<u64>,
+    /// Overwrite dumps before removing them.
+    #[serde(default)]
+    pub sanitize: bool,
+}
+
+fn default_dirs() -> Vec<PathBuf> {
+    vec![PathBuf::from("/var/crash"), PathBuf::from("/var/lib/systemd/coredump")]
+}
+
+fn default_keep_per_executable() -> usize {
+    3
+}
+
+#[derive(Debug, Clone)]
+struct Dump {
+    path: PathBuf,
+    executable: String,
+    modified: SystemTime,
+    size: u64,
+}
+
+impl CoreDumpsTask {
+    /// Dumps to remove, with their size and why. Blocking.
+    fn candidates(&self) -> Vec<(PathBuf, u64, String)> {
+        let mut by_executable: BTreeMap<String, Vec<Dump>> = BTreeMap::new();
+        for dir in &self.dirs {
+            for dump in dumps_in(dir) {
+                by_executable.entry(dump.executable.clone()).or_default().push(dump);
+            }
+        }
+
+        let mut remove = Vec::new();
+        let mut kept = Vec::new();
+        for (executable, mut dumps) in by_executable {
+            dumps.sort_by_key(|dump| Reverse(dump.modified));
+            let older = dumps.split_off(self.keep_per_executable.min(dumps.len()));
+            for dump in older {
+                let reason = format!("older than the newest {} of {}", self.keep_per_executable, executable);
+                remove.push((dump.path, dump.size, reason));
+            }
+            kept.extend(dumps);
+        }
+
+        if let Some(budget) = self.max_total_bytes {
+            kept.sort_by_key(|dump| dump.modified);
+            let mut total: u64 = kept.iter().map(|dump| dump.size).sum();
+            for dump in kept {
+                if total <= budget {
+                    break;
+                }
+                total -= dump.size;
+                remove.push((dump.path, dump.size, format!("over the {} budget", format_bytes(budget))));
+            }
+        }
+        remove
+    }
+}
+
+fn dumps_in(dir: &Path) -> Vec<Dump> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let dir_key = dir.display().to_string();
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let executable = executable_of(&name).or_else(|| is_dump(&name).then(|| dir_key.clone()))?;
+            Some(Dump {
+                path: entry.path(),
+                executable,
+                modified: metadata.modified().ok()?,
+                size: metadata.len(),
+            })
+        })
+        .collect()
+}
+
+/// The crashed executable, for the formats that name it.
+fn executable_of(name: &str) -> Option<String> {
+    // systemd escapes dots in the command name, so it is the first field.
+    if let Some(rest) = name.strip_prefix("core.") {
+        let mut fields = rest.split('.');
+        let comm = fields.next()?;
+        if fields.count() >= 4 {
+            return Some(comm.to_string());
+        }
+    }
+    // `_usr_bin_foo.1000.crash`; the path's escaping can't be undone.
+    let (exe, uid) = name.strip_suffix(".crash")?.rsplit_once('.')?;
+    uid.bytes().all(|b| b.is_ascii_digit()).then(|| exe.to_string())
+}
+
+/// Dumps whose names do not say what crashed: `core`, `core.<pid>`,
+/// `*.core` and minidumps.
+fn is_dump(name: &str) -> bool {
+    name == "core"
+        || name.strip_prefix("core.").is_some_and(|pid| pid.bytes().all(|b| b.is_ascii_digit()))
+        || name.ends_with(".core")
+        || name.ends_with(".dmp")
+}
+
+/// Removes (or, with `dry_run`, sizes up) the dumps beyond what the task
+/// keeps, sanitizing them first if asked.
+pub async fn clean_core_dumps(net_mon: &NetMon, resume: &ResumeState, task: &CoreDumpsTask, dry_run: bool) -> Result<()> {
+    info!("Removing old core dumps...");
+    let scan = task.clone();
+    let candidates = tokio::task::spawn_blocking(move || scan.candidates()).await?;
+    info!("{} core dumps eligible", candidates.len());
+
+    for (path, size, reason) in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+            continue;
+        }
+        info!("Removing {}: {}", target, reason);
+        if task.sanitize {
+            // Does its own resume check and records its own actions,
+            // failures included.
+            if let Err(e) = secure_delete_file(net_mon, resume, &target).await {
+                if is_cancelled() {
+                    return Err(e);
+                }
+                warn!("Failed to sanitize {}: {:#}", target, e);
+                continue;
+            }
+        } else {
+            if skip_completed(net_mon, resume, &target).await? {
+                continue;
+            }
+            if !remove_and_record(net_mon, &path, size).await? {
+                continue;
+            }
+        }
+        if path.extension().is_some_and(|ext| ext == "crash") {
+            for sidecar in APPORT_SIDECARS {
+                let _ = tokio::fs::remove_file(path.with_extension(sidecar)).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executable_from_systemd_and_apport_names() {
+        let cases = [
+            ("core.nginx.33.8f1e2a7c40a94d1b9d2e5b7c8a6f0e1d.1234.1700000000000000.zst", Some("nginx")),
+            ("core.my\\x2eapp.1000.8f1e2a7c.77.1700000000000000", Some("my\\x2eapp")),
+            ("_usr_bin_python3.10.1000.crash", Some("_usr_bin_python3.10")),
+            ("_usr_sbin_cupsd.0.crash", Some("_usr_sbin_cupsd")),
+            ("core.1234", None),
+            ("core", None),
+            ("notes.crash", None),
+            ("_usr_bin_foo.abc.crash", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(executable_of(name).as_deref(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn anonymous_dumps() {
+        for name in ["core", "core.1234", "firefox.core", "crash-0001.dmp"] {
+            assert!(is_dump(name), "{}", name);
+        }
+        for name in ["core.txt", "corefile", "core.nginx.33", "dump.log"] {
+            assert!(!is_dump(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,393 @@
+//! Long-running mode: watches mount usage with `statvfs` and, when a
+//! mount crosses the high-water mark, runs the plan's tasks one at a time
+//! by priority until every full mount is back under the low-water mark.
+//! Each cycle is its own session, with its own journal and reports.
+//!
+//! Tasks with a `schedule` also run on that schedule, each run its own
+//! session too. A task never runs twice at once, whichever triggered it.
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, BoxFuture};
+use log::{info, warn, error};
+use parking_lot::Mutex;
+
+use crate::cli::DaemonArgs;
+use crate::config::Config;
+use crate::hooks::{with_hooks, Outcome};
+use crate::net_mon::{in_task, Action, ActionSender};
+use crate::plan::{Plan, PlanTask};
+use crate::privileges::{check_plan, require_privileges};
+use crate::schedule::Schedule;
+use crate::supervise::{cancel_on_signal, supervise, CancelToken};
+use crate::utils::format_bytes;
+use crate::{run_task, timed, SessionRun};
+
+/// Longest single sleep between schedule checks.
+const MAX_SCHEDULE_SLEEP: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct MountUsage {
+    pub size: u64,
+    pub used: u64,
+    pub available: u64,
+}
+
+impl MountUsage {
+    /// Used share of the space available to unprivileged users, as `df`
+    /// reports it.
+    pub fn percent(&self) -> f64 {
+        let usable = self.used + self.available;
+        if usable == 0 {
+            return 0.0;
+        }
+        self.used as f64 * 100.0 / usable as f64
+    }
+}
+
+/// Usage of the filesystem holding `path`.
+// The statvfs field types differ between platforms.
+#[allow(clippy::unnecessary_cast)]
+pub fn mount_usage(path: &Path) -> Result<MountUsage> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid mount path: {}", path.display()))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("statvfs failed for {}", path.display()));
+    }
+    let fragment = stat.f_frsize as u64;
+    let size = stat.f_blocks as u64 * fragment;
+    let free = stat.f_bfree as u64 * fragment;
+    Ok(MountUsage {
+        size,
+        used: size.saturating_sub(free),
+        available: stat.f_bavail as u64 * fragment,
+    })
+}
+
+/// Mounts at or above `threshold` percent.
+fn mounts_over(mounts: &[PathBuf], threshold: f64) -> Vec<(PathBuf, MountUsage)> {
+    mounts
+        .iter()
+        .filter_map(|mount| match mount_usage(mount) {
+            Ok(usage) => Some((mount.clone(), usage)),
+            Err(e) => {
+                warn!("{:#}", e);
+                None
+            }
+        })
+        .filter(|(_, usage)| usage.percent() >= threshold)
+        .collect()
+}
+
+/// Tasks running now, by name, with the sender of the session running
+/// them once it is open, so a run that would overlap is recorded in that
+/// session.
+#[derive(Default)]
+struct RunningTasks {
+    tasks: Mutex<HashMap<String, Option<ActionSender>>>,
+}
+
+impl RunningTasks {
+    /// Marks `task` as running, in the session of `sender` if it is open
+    /// yet. If it already runs elsewhere, fails with that session's sender
+    /// (`None` while that session is still opening).
+    fn claim(&self, task: &str, sender: Option<ActionSender>) -> Result<(), Option<ActionSender>> {
+        let mut tasks = self.tasks.lock();
+        if let Some(holder) = tasks.get(task) {
+            return Err(holder.clone());
+        }
+        tasks.insert(task.to_string(), sender);
+        Ok(())
+    }
+
+    /// Records the session a task claimed without one runs in.
+    fn attach(&self, task: &str, sender: ActionSender) {
+        if let Some(holder) = self.tasks.lock().get_mut(task) {
+            *holder = Some(sender);
+        }
+    }
+
+    fn holder(&self, task: &str) -> Option<ActionSender> {
+        self.tasks.lock().get(task).cloned().flatten()
+    }
+
+    fn release(&self, task: &str) {
+        self.tasks.lock().remove(task);
+    }
+}
+
+pub async fn run_daemon(config: &Config, args: DaemonArgs) -> Result<()> {
+    if !(0.0..=100.0).contains(&args.high_water) || args.low_water >= args.high_water {
+        bail!("Need 0 <= low-water < high-water <= 100, got {} and {}", args.low_water, args.high_water);
+    }
+    let plan = match &args.plan {
+        Some(path) => Plan::load(path)?,
+        None => Plan::builtin(),
+    };
+    for mount in &args.mounts {
+        mount_usage(mount)?;
+    }
+    check_plan(&plan.tasks);
+    info!(
+        "Watching {} every {}s (high {}%, low {}%)",
+        args.mounts.iter().map(|m| m.display().to_string()).collect::<Vec<_>>().join(", "),
+        args.interval,
+        args.high_water,
+        args.low_water
+    );
+
+    let shutdown = CancelToken::new();
+    cancel_on_signal(&shutdown)?;
+    let daemon = Daemon { config, plan: &plan, args: &args, running: RunningTasks::default(), shutdown };
+    let schedules = plan.tasks.iter().filter_map(|task| {
+        let schedule = task.schedule.as_ref()?;
+        info!("Task {} scheduled: {:?}", task.name, schedule);
+        Some(daemon.schedule_loop(task, schedule))
+    });
+
+    // Both loops return once the shutdown token is cancelled and their
+    // running tasks have stopped.
+    futures::join!(daemon.pressure_loop(), join_all(schedules));
+    info!("Daemon stopped");
+    Ok(())
+}
+
+/// What the daemon's loops share.
+struct Daemon<'a> {
+    config: &'a Config,
+    plan: &'a Plan,
+    args: &'a DaemonArgs,
+    running: RunningTasks,
+    shutdown: CancelToken,
+}
+
+impl Daemon<'_> {
+    async fn start_session(&self) -> Result<SessionRun> {
+        SessionRun::start(self.config, self.plan, &self.shutdown, None, false, self.args.log_capacity).await
+    }
+
+    async fn run_supervised(&self, run: &SessionRun, task: &PlanTask) -> Result<Outcome> {
+        let (net_mon, resume) = (&run.net_mon, &run.resume);
+        let supervised = supervise(net_mon, task, &run.cancel, || run_task(net_mon, resume, task, false));
+        timed(net_mon, &task.name, async {
+            require_privileges(net_mon, task).await?;
+            with_hooks(net_mon, self.plan, task, &run.cancel, false, supervised).await
+        })
+        .await
+    }
+
+    async fn pressure_loop(&self) {
+        let args = self.args;
+        let interval = Duration::from_secs(args.interval.max(1));
+        while !self.shutdown.is_cancelled() {
+            let full = mounts_over(&args.mounts, args.high_water);
+            if !full.is_empty() {
+                for (mount, usage) in &full {
+                    info!("{} at {:.1}% of {}, starting a cleanup cycle", mount.display(), usage.percent(), format_bytes(usage.size));
+                }
+                let mounts: Vec<PathBuf> = full.into_iter().map(|(mount, _)| mount).collect();
+                if let Err(e) = self.run_cycle(&mounts).await {
+                    error!("Cleanup cycle failed: {:#}", e);
+                }
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.shutdown.cancelled() => {}
+            }
+        }
+    }
+
+    /// One session: tasks by priority until every mount in `mounts` is under
+    /// the low-water mark. Tasks already running on their schedule are
+    /// recorded as `OverlappingRun` and left alone.
+    async fn run_cycle(&self, mounts: &[PathBuf]) -> Result<()> {
+        let (args, running) = (self.args, &self.running);
+        let run = self.start_session().await?;
+        let mut task_failed = false;
+        let deps = self.plan.dependencies()?;
+        let mut failed: HashSet<usize> = HashSet::new();
+        let mut skipped: HashSet<usize> = HashSet::new();
+
+        for task in self.plan.ordered()? {
+            if run.cancel.is_cancelled() {
+                break;
+            }
+            let index = self.plan.tasks.iter().position(|t| t.name == task.name).expect("task is in its plan");
+            if let Some(&dep) = deps[index].iter().find(|d| failed.contains(d)) {
+                let dependency = self.plan.tasks[dep].name.clone();
+                warn!("Not running {}: {} failed", task.name, dependency);
+                run.net_mon.get_sender().send(Action::DependencyFailed(task.name.clone(), dependency)).await?;
+                failed.insert(index);
+                continue;
+            }
+            if let Some(&dep) = deps[index].iter().find(|d| skipped.contains(d)) {
+                let dependency = &self.plan.tasks[dep].name;
+                info!("Skipping {}: {} was skipped", task.name, dependency);
+                run.net_mon.get_sender().send(Action::Skipped(format!("{}: runs after skipped {}", task.name, dependency))).await?;
+                skipped.insert(index);
+                continue;
+            }
+            if mounts_over(mounts, args.low_water).is_empty() {
+                info!("Usage below {}%, ending cycle before {}", args.low_water, task.name);
+                break;
+            }
+            if running.claim(&task.name, Some(run.net_mon.get_sender())).is_err() {
+                info!("Task {} is already running, skipping it this cycle", task.name);
+                in_task(&task.name, run.net_mon.get_sender().send(Action::OverlappingRun(task.name.clone(), Utc::now()))).await?;
+                continue;
+            }
+            let result = self.run_supervised(&run, task).await;
+            running.release(&task.name);
+            match result {
+                Ok(Outcome::Ran) => {}
+                Ok(Outcome::Skipped) => {
+                    skipped.insert(index);
+                }
+                Err(e) => {
+                    error!("Task {} failed: {:#}", task.name, e);
+                    task_failed = true;
+                    failed.insert(index);
+                }
+            }
+        }
+
+        for (mount, usage) in mounts.iter().filter_map(|m| mount_usage(m).ok().map(|u| (m, u))) {
+            if usage.percent() >= args.low_water {
+                warn!("{} still at {:.1}% after running the plan", mount.display(), usage.percent());
+            }
+        }
+        let summary = run.finish(task_failed, &args.formats).await?;
+        info!("{}", summary);
+        Ok(())
+    }
+
+    /// Runs one task on its schedule, each run in its own session. Run times
+    /// that passed unnoticed (host suspended, daemon fell behind) are recorded as
+    /// `MissedRun` in the next run; run times that come while the task is
+    /// still running are recorded as `OverlappingRun` in the running session.
+    async fn schedule_loop(&self, task: &PlanTask, schedule: &Schedule) {
+        let running = &self.running;
+        let Some(mut next) = schedule.next_after(Utc::now()) else {
+            warn!("Schedule of task {} never fires", task.name);
+            return;
+        };
+        let mut missed = Vec::new();
+        let mut current: Option<BoxFuture<'_, ()>> = None;
+
+        loop {
+            // Sleep in bounded steps: the timer does not advance while the host
+            // is suspended, the wall clock does.
+            let wait = (next - Utc::now()).to_std().unwrap_or(Duration::ZERO).min(MAX_SCHEDULE_SLEEP);
+            let run_finished = tokio::select! {
+                _ = tokio::time::sleep(wait) => false,
+                _ = async { if let Some(run) = current.as_mut() { run.await } }, if current.is_some() => true,
+                _ = self.shutdown.cancelled() => {
+                    // The run's own token is a child of ours: let it stop.
+                    if let Some(run) = current.take() {
+                        run.await;
+                    }
+                    return;
+                }
+            };
+            if run_finished {
+                current = None;
+                continue;
+            }
+            let now = Utc::now();
+            if now < next {
+                continue;
+            }
+
+            let mut due = next;
+            loop {
+                match schedule.next_after(due) {
+                    Some(after) if after <= now => {
+                        missed.push(due);
+                        due = after;
+                    }
+                    Some(after) => {
+                        next = after;
+                        break;
+                    }
+                    None => {
+                        next = DateTime::<Utc>::MAX_UTC;
+                        break;
+                    }
+                }
+            }
+
+            // Our own last run may still be writing its reports after
+            // releasing the task; it is not claimed again until it is done.
+            let busy = match current {
+                Some(_) => Some(running.holder(&task.name)),
+                None => running.claim(&task.name, None).err(),
+            };
+            if let Some(holder) = busy {
+                info!("Task {} is still running, skipping its {} run", task.name, due);
+                match holder {
+                    Some(holder) => {
+                        let _ = in_task(&task.name, holder.send(Action::OverlappingRun(task.name.clone(), due))).await;
+                    }
+                    None => missed.push(due),
+                }
+                continue;
+            }
+            current = Some(Box::pin(self.scheduled_run(task, std::mem::take(&mut missed))));
+        }
+    }
+
+    /// One run of a task `schedule_loop` has claimed; releases the claim.
+    async fn scheduled_run(&self, task: &PlanTask, missed: Vec<DateTime<Utc>>) {
+        let (args, running) = (self.args, &self.running);
+        let run = match self.start_session().await {
+            Ok(run) => run,
+            Err(e) => {
+                running.release(&task.name);
+                error!("Scheduled run of {} failed: {:#}", task.name, e);
+                return;
+            }
+        };
+        running.attach(&task.name, run.net_mon.get_sender());
+        let result = async {
+            for at in missed {
+                warn!("Task {} missed its {} run", task.name, at);
+                in_task(&task.name, run.net_mon.get_sender().send(Action::MissedRun(task.name.clone(), at))).await?;
+            }
+            self.run_supervised(&run, task).await
+        }
+        .await;
+        running.release(&task.name);
+        if let Err(e) = &result {
+            error!("Scheduled task {} failed: {:#}", task.name, e);
+        }
+        match run.finish(result.is_err(), &args.formats).await {
+            Ok(summary) => info!("{}", summary),
+            Err(e) => error!("Scheduled run of {} failed: {:#}", task.name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net_mon::NetMon;
+
+    #[test]
+    fn a_claimed_task_is_held_until_released() {
+        let running = RunningTasks::default();
+        let net_mon = NetMon::new();
+        assert!(running.claim("tmp", None).is_ok());
+        assert!(matches!(running.claim("tmp", Some(net_mon.get_sender())), Err(None)));
+        running.attach("tmp", net_mon.get_sender());
+        assert!(matches!(running.claim("tmp", None), Err(Some(_))));
+        running.release("tmp");
+        assert!(running.holder("tmp").is_none());
+        assert!(running.claim("tmp", Some(net_mon.get_sender())).is_ok());
+    }
+}
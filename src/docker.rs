@@ -0,0 +1,594 @@
+//! Container engine cleanup over the engine's unix socket (Docker API,
+//! also served by Podman's compat socket): stopped containers, dangling
+//! images, unused volumes and build cache, each with an age filter.
+//!
+//! Speaks just enough HTTP/1.1 for the engine API, so any server on a unix
+//! socket that answers those endpoints can stand in for the engine.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Utc};
+use log::{info, error};
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+
+const API_HOST: &str = "docker";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockerTask {
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// Only remove objects created (build cache: last used) at least this
+    /// many hours ago.
+    #[serde(default)]
+    pub older_than_hours: Option<u64>,
+    #[serde(default = "enabled")]
+    pub containers: bool,
+    #[serde(default = "enabled")]
+    pub images: bool,
+    #[serde(default = "enabled")]
+    pub volumes: bool,
+    #[serde(default = "enabled")]
+    pub build_cache: bool,
+}
+
+impl Default for DockerTask {
+    fn default() -> Self {
+        Self {
+            socket: default_socket(),
+            older_than_hours: None,
+            containers: true,
+            images: true,
+            volumes: true,
+            build_cache: true,
+        }
+    }
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from("/var/run/docker.sock")
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    id: String,
+    #[serde(default)]
+    names: Vec<String>,
+    created: i64,
+    #[serde(default)]
+    size_rw: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Image {
+    id: String,
+    created: i64,
+    #[serde(default)]
+    size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VolumeList {
+    #[serde(default)]
+    volumes: Option<Vec<Volume>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Volume {
+    name: String,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+}
+
+/// The parts of `/system/df` used for sizes the list endpoints don't give.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DiskUsage {
+    #[serde(default)]
+    volumes: Option<Vec<VolumeUsage>>,
+    #[serde(default)]
+    build_cache: Option<Vec<BuildCacheRecord>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct VolumeUsage {
+    name: String,
+    #[serde(default)]
+    usage_data: Option<UsageData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct UsageData {
+    size: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BuildCacheRecord {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "Size", default)]
+    size: u64,
+    #[serde(rename = "InUse", default)]
+    in_use: bool,
+    #[serde(rename = "LastUsedAt", default)]
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BuildPruneResponse {
+    #[serde(default)]
+    caches_deleted: Option<Vec<String>>,
+    #[serde(default)]
+    space_reclaimed: u64,
+}
+
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+/// Minimal engine API client: one connection per request.
+pub struct EngineClient {
+    socket: PathBuf,
+}
+
+impl EngineClient {
+    pub fn new(socket: &Path) -> Self {
+        Self { socket: socket.to_path_buf() }
+    }
+
+    async fn request(&self, method: &str, path: &str) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("Failed to connect to engine socket: {}", self.socket.display()))?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path, API_HOST
+        );
+        stream.write_all(request.as_bytes()).await.context("Failed to send engine request")?;
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).await.context("Failed to read engine response")?;
+        parse_response(&raw).with_context(|| format!("Bad engine response to {} {}", method, path))
+    }
+
+    async fn call(&self, method: &str, path: &str) -> Result<Vec<u8>> {
+        let response = self.request(method, path).await?;
+        if response.status >= 400 {
+            bail!(
+                "{} {} failed with {}: {}",
+                method,
+                path,
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            );
+        }
+        Ok(response.body)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let body = self.call("GET", path).await?;
+        serde_json::from_slice(&body).with_context(|| format!("Unexpected engine response to GET {}", path))
+    }
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response> {
+    let header_end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("No end of headers")?;
+    let head = String::from_utf8_lossy(&raw[..header_end]);
+    let mut lines = head.split("\r\n");
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .context("No status line")?;
+
+    let mut chunked = false;
+    let mut content_length = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else { continue };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse::<usize>().ok();
+        }
+    }
+
+    let rest = &raw[header_end + 4..];
+    let body = if chunked {
+        decode_chunked(rest)?
+    } else {
+        let len = content_length.unwrap_or(rest.len()).min(rest.len());
+        rest[..len].to_vec()
+    };
+    Ok(Response { status, body })
+}
+
+fn decode_chunked(mut data: &[u8]) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n").context("Truncated chunk size")?;
+        let size_field = String::from_utf8_lossy(&data[..line_end]);
+        let size_hex = size_field.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).context("Bad chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if data.len() < size {
+            bail!("Truncated chunk");
+        }
+        body.extend_from_slice(&data[..size]);
+        data = data.get(size + 2..).unwrap_or(&[]);
+    }
+}
+
+/// Percent-encodes a query value (RFC 3986 unreserved characters pass).
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn filters(json: serde_json::Value) -> String {
+    encode_query(&json.to_string())
+}
+
+/// One object the task would remove.
+struct Candidate {
+    target: String,
+    method: &'static str,
+    path: String,
+    size: u64,
+}
+
+impl DockerTask {
+    fn cutoff(&self) -> Option<DateTime<Utc>> {
+        self.older_than_hours.map(|hours| Utc::now() - chrono::Duration::hours(hours as i64))
+    }
+
+    fn old_enough(&self, created: Option<DateTime<Utc>>) -> bool {
+        match (self.cutoff(), created) {
+            (Some(cutoff), Some(created)) => created < cutoff,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+
+    async fn candidates(&self, client: &EngineClient, usage: &DiskUsage) -> Result<Vec<Candidate>> {
+        let mut candidates = Vec::new();
+
+        if self.containers {
+            let query = filters(serde_json::json!({ "status": ["exited", "created", "dead"] }));
+            let containers: Vec<Container> = client.get(&format!("/containers/json?all=true&size=true&filters={}", query)).await?;
+            for container in containers {
+                if !self.old_enough(DateTime::from_timestamp(container.created, 0)) {
+                    continue;
+                }
+                let name = container.names.first().map(|n| n.trim_start_matches('/')).unwrap_or("");
+                candidates.push(Candidate {
+                    target: format!("container:{} {}", short_id(&container.id), name).trim_end().to_string(),
+                    method: "DELETE",
+                    path: format!("/containers/{}", container.id),
+                    size: container.size_rw.unwrap_or(0),
+                });
+            }
+        }
+
+        if self.images {
+            let query = filters(serde_json::json!({ "dangling": ["true"] }));
+            let images: Vec<Image> = client.get(&format!("/images/json?filters={}", query)).await?;
+            for image in images {
+                if !self.old_enough(DateTime::from_timestamp(image.created, 0)) {
+                    continue;
+                }
+                candidates.push(Candidate {
+                    target: format!("image:{}", short_id(&image.id)),
+                    method: "DELETE",
+                    path: format!("/images/{}", image.id),
+                    size: image.size,
+                });
+            }
+        }
+
+        if self.volumes {
+            let sizes: HashMap<&str, u64> = usage
+                .volumes
+                .iter()
+                .flatten()
+                .map(|v| (v.name.as_str(), v.usage_data.as_ref().map_or(0, |u| u.size.max(0) as u64)))
+                .collect();
+            let query = filters(serde_json::json!({ "dangling": ["true"] }));
+            let list: VolumeList = client.get(&format!("/volumes?filters={}", query)).await?;
+            for volume in list.volumes.unwrap_or_default() {
+                if !self.old_enough(volume.created_at) {
+                    continue;
+                }
+                candidates.push(Candidate {
+                    target: format!("volume:{}", volume.name),
+                    method: "DELETE",
+                    path: format!("/volumes/{}", encode_query(&volume.name)),
+                    size: sizes.get(volume.name.as_str()).copied().unwrap_or(0),
+                });
+            }
+        }
+        Ok(candidates)
+    }
+
+    fn build_cache_candidates<'a>(&self, usage: &'a DiskUsage) -> Vec<&'a BuildCacheRecord> {
+        usage
+            .build_cache
+            .iter()
+            .flatten()
+            .filter(|record| !record.in_use && self.old_enough(record.last_used_at))
+            .collect()
+    }
+}
+
+fn short_id(id: &str) -> &str {
+    let id = id.strip_prefix("sha256:").unwrap_or(id);
+    &id[..id.len().min(12)]
+}
+
+/// Prunes (or, with `dry_run`, sizes up) engine objects.
+pub async fn clean_docker(net_mon: &NetMon, resume: &ResumeState, task: &DockerTask, dry_run: bool) -> Result<()> {
+    info!("Cleaning container engine objects via {}...", task.socket.display());
+    let client = EngineClient::new(&task.socket);
+
+    let usage: DiskUsage = if task.volumes || task.build_cache {
+        client.get("/system/df").await?
+    } else {
+        DiskUsage::default()
+    };
+
+    for candidate in task.candidates(&client, &usage).await? {
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(candidate.target, candidate.size)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &candidate.target).await? {
+            continue;
+        }
+        match client.call(candidate.method, &candidate.path).await {
+            Ok(_) => {
+                info!("Removed {}", candidate.target);
+                net_mon.get_sender().send(Action::ObjectRemoved(candidate.target.clone())).await?;
+                net_mon.get_sender().send(Action::SpaceFreed(candidate.target, candidate.size)).await?;
+            }
+            Err(e) => {
+                error!("Error removing {}: {:#}", candidate.target, e);
+                net_mon.get_sender().send(Action::TargetFailed(candidate.target, format!("{:#}", e))).await?;
+            }
+        }
+    }
+
+    if task.build_cache {
+        let records = task.build_cache_candidates(&usage);
+        if dry_run {
+            for record in records {
+                net_mon.get_sender().send(Action::Planned(format!("build_cache:{}", short_id(&record.id)), record.size)).await?;
+            }
+        } else if !records.is_empty() {
+            prune_build_cache(net_mon, &client, task, &usage).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The engine has no per-record delete for build cache; prune with the
+/// same age filter and report what it deleted.
+async fn prune_build_cache(net_mon: &NetMon, client: &EngineClient, task: &DockerTask, usage: &DiskUsage) -> Result<()> {
+    let mut path = "/build/prune".to_string();
+    if let Some(hours) = task.older_than_hours {
+        path.push_str(&format!("?filters={}", filters(serde_json::json!({ "until": [format!("{}h", hours)] }))));
+    }
+    let body = match client.call("POST", &path).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!("Error pruning build cache: {:#}", e);
+            net_mon.get_sender().send(Action::TargetFailed("build_cache".to_string(), format!("{:#}", e))).await?;
+            return Ok(());
+        }
+    };
+    let pruned: BuildPruneResponse = serde_json::from_slice(&body).context("Unexpected build prune response")?;
+
+    let sizes: HashMap<&str, u64> = usage.build_cache.iter().flatten().map(|r| (r.id.as_str(), r.size)).collect();
+    let deleted = pruned.caches_deleted.unwrap_or_default();
+    let mut attributed = 0;
+    for id in &deleted {
+        let size = sizes.get(id.as_str()).copied().unwrap_or(0);
+        attributed += size;
+        let target = format!("build_cache:{}", short_id(id));
+        net_mon.get_sender().send(Action::ObjectRemoved(target.clone())).await?;
+        net_mon.get_sender().send(Action::SpaceFreed(target, size)).await?;
+    }
+    // Records created after the `/system/df` snapshot have no known size.
+    if pruned.space_reclaimed > attributed {
+        net_mon.get_sender().send(Action::SpaceFreed("build_cache".to_string(), pruned.space_reclaimed - attributed)).await?;
+    }
+    info!("Pruned {} build cache records", deleted.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use parking_lot::Mutex;
+    use tokio::net::UnixListener;
+    use uuid::Uuid;
+
+    fn content_length(status: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body)
+    }
+
+    /// `body` split into chunks at `splits`, with chunk extensions.
+    fn chunked(body: &str, splits: &[usize]) -> String {
+        let mut out = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_string();
+        let mut start = 0;
+        for &end in splits.iter().chain(std::iter::once(&body.len())) {
+            let chunk = &body[start..end];
+            out.push_str(&format!("{:x};ext=\"x\"\r\n{}\r\n", chunk.len(), chunk));
+            start = end;
+        }
+        out.push_str("0\r\n\r\n");
+        out
+    }
+
+    /// Canned answer to a request line.
+    fn respond(method: &str, path: &str) -> String {
+        match (method, path.split('?').next().unwrap_or(path)) {
+            ("GET", "/system/df") => content_length(
+                "200 OK",
+                r#"{"Volumes":[{"Name":"cache","UsageData":{"Size":300}},{"Name":"gone","UsageData":{"Size":40}}],
+                   "BuildCache":[{"ID":"abc123","Size":100,"InUse":false},{"ID":"busy","Size":70,"InUse":true}]}"#,
+            ),
+            ("GET", "/containers/json") => {
+                let body = r#"[{"Id":"c0ffee1234567890","Names":["/old-job"],"Created":1600000000,"SizeRw":1000}]"#;
+                chunked(body, &[5, 30])
+            }
+            ("GET", "/images/json") => content_length("200 OK", r#"[{"Id":"sha256:deadbeef00112233","Created":1600000000,"Size":5000}]"#),
+            ("GET", "/volumes") => content_length("200 OK", r#"{"Volumes":[{"Name":"cache"},{"Name":"gone"}]}"#),
+            ("DELETE", "/containers/c0ffee1234567890") => "HTTP/1.1 204 No Content\r\n\r\n".to_string(),
+            ("DELETE", "/images/sha256:deadbeef00112233") => content_length("200 OK", r#"[{"Deleted":"sha256:deadbeef00112233"}]"#),
+            ("DELETE", "/volumes/cache") => "HTTP/1.1 204 No Content\r\n\r\n".to_string(),
+            ("DELETE", "/volumes/gone") => content_length("404 Not Found", r#"{"message":"get gone: no such volume"}"#),
+            ("POST", "/build/prune") => content_length("200 OK", r#"{"CachesDeleted":["abc123"],"SpaceReclaimed":150}"#),
+            _ => content_length("500 Internal Server Error", r#"{"message":"unexpected request"}"#),
+        }
+    }
+
+    /// Serves canned responses on a unix socket, recording request lines.
+    async fn mock_engine(socket: &Path) -> Arc<Mutex<Vec<String>>> {
+        let listener = UnixListener::bind(socket).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut stream, _)) = listener.accept().await else { return };
+                let mut raw = Vec::new();
+                let mut buf = [0; 4096];
+                while !raw.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => raw.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let head = String::from_utf8_lossy(&raw).into_owned();
+                let line = head.lines().next().unwrap_or("").to_string();
+                let mut parts = line.split_whitespace();
+                let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                let response = respond(method, path);
+                seen.lock().push(format!("{} {}", method, path));
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        requests
+    }
+
+    #[test]
+    fn parses_content_length_and_chunked_bodies() {
+        let response = parse_response(content_length("200 OK", "{}extra").as_bytes()).unwrap();
+        assert_eq!((response.status, response.body.as_slice()), (200, b"{}extra".as_slice()));
+
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}trailing";
+        assert_eq!(parse_response(raw.as_bytes()).unwrap().body, b"{}");
+
+        let response = parse_response(chunked("hello, chunked world", &[3, 9]).as_bytes()).unwrap();
+        assert_eq!(response.body, b"hello, chunked world");
+
+        assert!(decode_chunked(b"5\r\nab").is_err());
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn removes_through_engine_api() {
+        let dir = std::env::temp_dir().join(format!("docker-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("engine.sock");
+        let requests = mock_engine(&socket).await;
+
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let task = DockerTask { socket: socket.clone(), ..DockerTask::default() };
+        clean_docker(&net_mon, &ResumeState::default(), &task, false).await.unwrap();
+        net_mon.close().await;
+
+        let actions: Vec<String> = net_mon
+            .get_actions()
+            .await
+            .into_iter()
+            .map(|record| format!("{} {}", record.action.kind(), record.action.detail()))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                "ObjectRemoved container:c0ffee123456 old-job",
+                "SpaceFreed container:c0ffee123456 old-job (1000 bytes)",
+                "ObjectRemoved image:deadbeef0011",
+                "SpaceFreed image:deadbeef0011 (5000 bytes)",
+                "ObjectRemoved volume:cache",
+                "SpaceFreed volume:cache (300 bytes)",
+                "TargetFailed volume:gone: DELETE /volumes/gone failed with 404: {\"message\":\"get gone: no such volume\"}",
+                "ObjectRemoved build_cache:abc123",
+                "SpaceFreed build_cache:abc123 (100 bytes)",
+                "SpaceFreed build_cache (50 bytes)",
+            ]
+        );
+
+        let requests = requests.lock().clone();
+        for expected in ["DELETE /containers/c0ffee1234567890", "DELETE /images/sha256:deadbeef00112233", "DELETE /volumes/cache", "POST /build/prune"] {
+            assert!(requests.iter().any(|request| request == expected), "{:?}", requests);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dry_run_only_sizes_up() {
+        let dir = std::env::temp_dir().join(format!("docker-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("engine.sock");
+        let requests = mock_engine(&socket).await;
+
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let task = DockerTask { socket: socket.clone(), ..DockerTask::default() };
+        clean_docker(&net_mon, &ResumeState::default(), &task, true).await.unwrap();
+        net_mon.close().await;
+
+        let planned: u64 = net_mon
+            .get_actions()
+            .await
+            .iter()
+            .map(|record| match record.action {
+                Action::Planned(_, size) => size,
+                ref other => panic!("unexpected {:?}", other),
+            })
+            .sum();
+        assert_eq!(planned, 1000 + 5000 + 300 + 40 + 100);
+        assert!(requests.lock().iter().all(|request| request.starts_with("GET ")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
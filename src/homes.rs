@@ -0,0 +1,239 @@
+//! Cleanup across users' home directories: the per-home caches and the
+//! trash of every user in `/etc/passwd`, or of the users the plan lists.
+//!
+//! Each home is scanned and cleaned as its owner, on a thread switched to
+//! the user's uid and gid, so permissions are checked as if the user did it
+//! themselves. On top of that, a home must be owned by its user and only
+//! entries the user owns are removed. Every action is tagged with the user
+//! for the per-user reports.
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use anyhow::{Result, Context};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::caches::{CacheKind, CacheTask};
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{for_user, Action, NetMon};
+use crate::privileges::{lookup_user, run_as, User};
+use crate::trash::{self, TrashDir};
+use crate::utils::dir_size;
+
+const PASSWD: &str = "/etc/passwd";
+/// `nobody`, which has a uid in the user range on most systems.
+const NOBODY_UID: u32 = 65534;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserHomesTask {
+    /// Users to clean; empty means every user in `/etc/passwd` with a uid
+    /// of at least `min_uid` and an existing home.
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default = "default_min_uid")]
+    pub min_uid: u32,
+    /// Caches to clean in each home (cargo, pip, npm, yarn, thumbnails).
+    #[serde(default)]
+    pub caches: Vec<CacheKind>,
+    /// Empty each user's trash.
+    #[serde(default)]
+    pub trash: bool,
+    /// Only remove entries untouched (or trashed) this many days ago.
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+}
+
+fn default_min_uid() -> u32 {
+    1000
+}
+
+impl UserHomesTask {
+    fn users(&self) -> Result<Vec<User>> {
+        if !self.users.is_empty() {
+            return self.users.iter().map(|name| lookup_user(name)).collect();
+        }
+        let passwd = fs::read_to_string(PASSWD).with_context(|| format!("Failed to read {}", PASSWD))?;
+        Ok(passwd
+            .lines()
+            .filter_map(parse_passwd_line)
+            .filter(|user| user.uid >= self.min_uid && user.uid != NOBODY_UID && user.home.is_dir())
+            .collect())
+    }
+
+    /// Entries of `home` old enough to remove, with their size. Blocking;
+    /// runs as the home's user.
+    fn candidates(&self, home: &Path) -> Vec<(PathBuf, u64)> {
+        let mut found = Vec::new();
+        for &cache in &self.caches {
+            let cache = CacheTask {
+                cache,
+                older_than_days: self.older_than_days,
+                homes: vec![home.to_path_buf()],
+                target_roots: Vec::new(),
+            };
+            found.extend(cache.candidates());
+        }
+        if self.trash {
+            found.extend(self.trash_candidates(home));
+        }
+        found
+    }
+
+    /// Trashed items, aged by their `DeletionDate`.
+    fn trash_candidates(&self, home: &Path) -> Vec<(PathBuf, u64)> {
+        let cutoff = trash::cutoff(self.older_than_days);
+        TrashDir::in_home(home)
+            .items()
+            .into_iter()
+            .filter(|item| item.trashed_before(cutoff) && fs::symlink_metadata(&item.file).is_ok())
+            .map(|item| {
+                let size = dir_size(&item.file);
+                (item.file, size)
+            })
+            .collect()
+    }
+}
+
+/// `name:password:uid:gid:gecos:home:shell`
+fn parse_passwd_line(line: &str) -> Option<User> {
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() < 7 || line.starts_with('#') {
+        return None;
+    }
+    Some(User {
+        name: fields[0].to_string(),
+        uid: fields[2].parse().ok()?,
+        gid: fields[3].parse().ok()?,
+        home: PathBuf::from(fields[5]),
+    })
+}
+
+/// Outcome of removing one entry as its user.
+struct Removal {
+    target: String,
+    size: u64,
+    elapsed_ms: u64,
+    error: Option<String>,
+}
+
+/// Removes `entries` that `uid` owns. Blocking; runs as that user.
+fn remove_owned(entries: Vec<(PathBuf, u64)>, uid: u32) -> Vec<Removal> {
+    entries
+        .into_iter()
+        .map(|(path, size)| {
+            let started = Instant::now();
+            let removed = match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.uid() != uid => Err(format!("owned by uid {}, not {}", metadata.uid(), uid)),
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path).map_err(|e| format!("Failed to remove: {}", e)),
+                Ok(_) => fs::remove_file(&path).map_err(|e| format!("Failed to remove: {}", e)),
+                Err(e) => Err(format!("Failed to remove: {}", e)),
+            };
+            if removed.is_ok() {
+                if let Some(info) = trash::info_path(&path) {
+                    let _ = fs::remove_file(info);
+                }
+            }
+            Removal {
+                target: path.display().to_string(),
+                size,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                error: removed.err(),
+            }
+        })
+        .collect()
+}
+
+/// Cleans (or, with `dry_run`, sizes up) each user's home in turn.
+pub async fn clean_user_homes(net_mon: &NetMon, resume: &ResumeState, task: &UserHomesTask, dry_run: bool) -> Result<()> {
+    let users = task.users()?;
+    info!("Cleaning the homes of {} users", users.len());
+    for user in &users {
+        for_user(&user.name, clean_home(net_mon, resume, task, user, dry_run)).await?;
+    }
+    Ok(())
+}
+
+async fn clean_home(net_mon: &NetMon, resume: &ResumeState, task: &UserHomesTask, user: &User, dry_run: bool) -> Result<()> {
+    let home = user.home.display().to_string();
+    match tokio::fs::symlink_metadata(&user.home).await {
+        Ok(metadata) if metadata.is_dir() && metadata.uid() == user.uid => {}
+        Ok(metadata) => {
+            warn!("Skipping {}: {} is not a dir owned by them", user.name, home);
+            let reason = format!("not a home dir owned by {} (owner uid {})", user.name, metadata.uid());
+            net_mon.get_sender().send(Action::TargetFailed(home, reason)).await?;
+            return Ok(());
+        }
+        Err(e) => {
+            net_mon.get_sender().send(Action::TargetFailed(home, e.to_string())).await?;
+            return Ok(());
+        }
+    }
+
+    let scan = task.clone();
+    let scan_home = user.home.clone();
+    let candidates = match run_as(user, move || scan.candidates(&scan_home)).await {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            warn!("Skipping {}: {:#}", user.name, e);
+            net_mon.get_sender().send(Action::TargetFailed(home, format!("{:#}", e))).await?;
+            return Ok(());
+        }
+    };
+    info!("{} entries eligible in {}", candidates.len(), home);
+
+    let mut entries = Vec::new();
+    for (path, size) in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+        } else if !skip_completed(net_mon, resume, &target).await? {
+            entries.push((path, size));
+        }
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let uid = user.uid;
+    let removals = match run_as(user, move || remove_owned(entries, uid)).await {
+        Ok(removals) => removals,
+        Err(e) => {
+            net_mon.get_sender().send(Action::TargetFailed(home, format!("{:#}", e))).await?;
+            return Ok(());
+        }
+    };
+    for removal in removals {
+        match removal.error {
+            None => {
+                net_mon.get_sender().send(Action::FileDeleted(removal.target.clone())).await?;
+                net_mon.get_sender().send(Action::SpaceFreed(removal.target.clone(), removal.size)).await?;
+                net_mon.get_sender().send(Action::ItemTimed(removal.target, removal.elapsed_ms)).await?;
+            }
+            Some(reason) => {
+                warn!("Not removing {}: {}", removal.target, reason);
+                net_mon.get_sender().send(Action::TargetFailed(removal.target, reason)).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwd_lines() {
+        let user = parse_passwd_line("alice:x:1000:100:Alice,,,:/home/alice:/bin/bash").unwrap();
+        assert_eq!((user.name.as_str(), user.uid, user.gid), ("alice", 1000, 100));
+        assert_eq!(user.home, Path::new("/home/alice"));
+
+        let nobody = parse_passwd_line("nobody:x:65534:65534::/nonexistent:").unwrap();
+        assert_eq!(nobody.home, Path::new("/nonexistent"));
+
+        for line in ["", "# alice:x:1000:100::/home/alice:/bin/bash", "alice:x:1000:100:/home/alice", "alice:x:uid:100::/home/alice:/bin/sh"] {
+            assert!(parse_passwd_line(line).is_none(), "{}", line);
+        }
+    }
+}
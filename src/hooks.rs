@@ -0,0 +1,282 @@
+//! Site-specific commands run around a task, e.g. stopping a service
+//! before clearing its cache and starting it again after. Hooks are
+//! defined once under `"hooks"` in the plan and listed by name in a task's
+//! `pre` and `post`.
+//!
+//! A failing pre-hook skips the task (and what depends on it); that is no
+//! failure of the run. Post-hooks run whether or not the task succeeded,
+//! even when the run is cancelled, so they can undo what the pre-hooks
+//! did; a failing one fails the task. Dry runs don't run hooks.
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use log::{info, warn, error};
+use serde::{Serialize, Deserialize};
+use tokio::process::Command;
+
+use crate::net_mon::{Action, NetMon};
+use crate::plan::{Plan, PlanTask};
+use crate::supervise::CancelToken;
+
+/// Output kept per stream; the rest is cut.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hook {
+    /// Program and arguments, run directly (no shell).
+    pub command: Vec<String>,
+    /// Extra environment; `CLEANUP_TASK` and `CLEANUP_HOOK_PHASE` are
+    /// always set.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Pre,
+    Post,
+}
+
+impl Phase {
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Pre => "pre",
+            Phase::Post => "post",
+        }
+    }
+}
+
+/// Whether a task ran, as far as its hooks are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ran,
+    /// A pre-hook failed, so the task did not run.
+    Skipped,
+}
+
+/// Runs `body` between the task's pre- and post-hooks. Pre-hooks stop when
+/// `cancel` is cancelled; post-hooks don't.
+pub async fn with_hooks<F>(net_mon: &NetMon, plan: &Plan, task: &PlanTask, cancel: &CancelToken, dry_run: bool, body: F) -> Result<Outcome>
+where
+    F: Future<Output = Result<()>>,
+{
+    if dry_run {
+        for name in task.pre.iter().chain(&task.post) {
+            info!("Dry run: not running hook {} of {}", name, task.name);
+        }
+        return body.await.map(|()| Outcome::Ran);
+    }
+
+    for name in &task.pre {
+        let failure = match run_hook(net_mon, plan, task, name, Phase::Pre, Some(cancel)).await? {
+            None => continue,
+            Some(failure) => failure,
+        };
+        if cancel.is_cancelled() {
+            let reason = format!("interrupted in pre-hook {}", name);
+            warn!("Task {} cancelled: {}", task.name, reason);
+            net_mon.get_sender().send(Action::Cancelled(task.name.clone(), reason.clone())).await?;
+            return Err(anyhow!("Task {} cancelled: {}", task.name, reason));
+        }
+        warn!("Skipping {}: pre-hook {} {}", task.name, name, failure);
+        net_mon.get_sender().send(Action::Skipped(format!("{}: pre-hook {} {}", task.name, name, failure))).await?;
+        return Ok(Outcome::Skipped);
+    }
+
+    let result = body.await;
+
+    let mut post_failed = None;
+    for name in &task.post {
+        if let Some(failure) = run_hook(net_mon, plan, task, name, Phase::Post, None).await? {
+            net_mon.get_sender().send(Action::HookFailed(name.to_string(), failure.clone())).await?;
+            post_failed.get_or_insert(anyhow!("Hook {} {}", name, failure));
+        }
+    }
+    match (result, post_failed) {
+        (Err(e), _) => Err(e),
+        (Ok(()), Some(e)) => Err(e),
+        (Ok(()), None) => Ok(Outcome::Ran),
+    }
+}
+
+/// Runs one hook and records its output. Returns why it failed, if it
+/// did: its exit status and stderr, a timeout, or `cancel`.
+async fn run_hook(net_mon: &NetMon, plan: &Plan, task: &PlanTask, name: &str, phase: Phase, cancel: Option<&CancelToken>) -> Result<Option<String>> {
+    let hook = plan.hooks.get(name).ok_or_else(|| anyhow!("Unknown hook {}", name))?;
+    let (program, args) = hook.command.split_first().ok_or_else(|| anyhow!("Hook {} has no command", name))?;
+    info!("Running {}-hook {} of {}: {}", phase.as_str(), name, task.name, hook.command.join(" "));
+
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .envs(&hook.env)
+        .env("CLEANUP_TASK", &task.name)
+        .env("CLEANUP_HOOK_PHASE", phase.as_str())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(cwd) = &hook.cwd {
+        command.current_dir(cwd);
+    }
+
+    let timeout = Duration::from_secs(hook.timeout_secs);
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => std::future::pending().await,
+        }
+    };
+    let finished = tokio::select! {
+        finished = tokio::time::timeout(timeout, command.output()) => finished,
+        // Dropping the output future kills the hook.
+        _ = cancelled => return Ok(Some("interrupted".to_string())),
+    };
+    let failure = match finished {
+        Err(_) => format!("timed out after {}s", hook.timeout_secs),
+        Ok(Err(e)) => format!("failed to start: {}", e),
+        Ok(Ok(output)) => {
+            let stderr = captured(&output.stderr);
+            net_mon
+                .get_sender()
+                .send(Action::HookFinished(name.to_string(), output.status.code(), captured(&output.stdout), stderr.clone()))
+                .await?;
+            if output.status.success() {
+                return Ok(None);
+            }
+            if stderr.is_empty() {
+                format!("exited with {}", output.status)
+            } else {
+                format!("exited with {}: {}", output.status, stderr)
+            }
+        }
+    };
+    error!("Hook {} of {}: {}", name, task.name, failure);
+    Ok(Some(failure))
+}
+
+fn captured(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_OUTPUT)]);
+    let mut text = text.trim_end().to_string();
+    if bytes.len() > MAX_OUTPUT {
+        text.push_str("\n[truncated]");
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::net_mon::ActionRecord;
+
+    fn plan(pre: &[&str], post: &[&str]) -> Plan {
+        serde_json::from_value(serde_json::json!({
+            "hooks": {
+                "ok": {"command": ["true"]},
+                "oops": {"command": ["sh", "-c", "echo oops >&2; exit 3"]},
+                "task": {"command": ["sh", "-c", "test \"$CLEANUP_TASK:$CLEANUP_HOOK_PHASE\" = t:post"]},
+                "slow": {"command": ["sleep", "30"]},
+                "stuck": {"command": ["sleep", "30"], "timeout_secs": 1}
+            },
+            "tasks": [{"name": "t", "task": "clear_bash_history", "pre": pre, "post": post}]
+        }))
+        .unwrap()
+    }
+
+    /// Runs task `t` of `plan` with a body that records it ran and returns
+    /// `body`, and the actions sent meanwhile.
+    async fn run(plan: &Plan, cancel: &CancelToken, body: Result<()>) -> (Result<Outcome>, bool, Vec<ActionRecord>) {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let ran = AtomicBool::new(false);
+        let result = with_hooks(&net_mon, plan, &plan.tasks[0], cancel, false, async {
+            ran.store(true, Ordering::SeqCst);
+            body
+        })
+        .await;
+        net_mon.close().await;
+        (result, ran.load(Ordering::SeqCst), net_mon.get_actions().await)
+    }
+
+    #[tokio::test]
+    async fn a_failing_pre_hook_skips_the_task() {
+        let (result, ran, actions) = run(&plan(&["ok", "oops", "ok"], &[]), &CancelToken::new(), Ok(())).await;
+        assert_eq!(result.unwrap(), Outcome::Skipped);
+        assert!(!ran);
+        let skipped: Vec<_> = actions.iter().filter_map(|r| match &r.action {
+            Action::Skipped(reason) => Some(reason.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].starts_with("t: pre-hook oops exited with"), "{}", skipped[0]);
+        assert!(skipped[0].ends_with(": oops"), "{}", skipped[0]);
+        assert!(!actions.iter().any(|r| matches!(r.action, Action::HookFailed(..))));
+        // The hook after the failing one doesn't run.
+        assert_eq!(actions.iter().filter(|r| matches!(r.action, Action::HookFinished(..))).count(), 2);
+    }
+
+    #[tokio::test]
+    async fn post_hooks_run_after_a_failed_task() {
+        let (result, ran, actions) = run(&plan(&["ok"], &["task"]), &CancelToken::new(), Err(anyhow!("body failed"))).await;
+        assert_eq!(result.unwrap_err().to_string(), "body failed");
+        assert!(ran);
+        assert!(actions.iter().any(|r| matches!(&r.action, Action::HookFinished(name, Some(0), ..) if name == "task")));
+    }
+
+    #[tokio::test]
+    async fn a_failing_post_hook_fails_the_task() {
+        let (result, ran, actions) = run(&plan(&[], &["oops", "ok"]), &CancelToken::new(), Ok(())).await;
+        assert!(result.is_err());
+        assert!(ran);
+        assert!(actions.iter().any(|r| matches!(&r.action, Action::HookFailed(name, _) if name == "oops")));
+        // Later post-hooks still run.
+        assert!(actions.iter().any(|r| matches!(&r.action, Action::HookFinished(name, ..) if name == "ok")));
+    }
+
+    #[tokio::test]
+    async fn a_hook_that_runs_too_long_skips_the_task() {
+        let (result, ran, actions) = run(&plan(&["stuck"], &[]), &CancelToken::new(), Ok(())).await;
+        assert_eq!(result.unwrap(), Outcome::Skipped);
+        assert!(!ran);
+        assert!(actions.iter().any(|r| matches!(&r.action, Action::Skipped(reason) if reason.ends_with("timed out after 1s"))));
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_a_pre_hook() {
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        let (result, ran, actions) = run(&plan(&["slow"], &["ok"]), &cancel, Ok(())).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(result.is_err());
+        assert!(!ran);
+        assert!(actions.iter().any(|r| matches!(&r.action, Action::Cancelled(task, reason) if task == "t" && reason == "interrupted in pre-hook slow")));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_run_no_hooks() {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let plan = plan(&["oops"], &["oops"]);
+        let result = with_hooks(&net_mon, &plan, &plan.tasks[0], &CancelToken::new(), true, async { Ok(()) }).await;
+        net_mon.close().await;
+        assert_eq!(result.unwrap(), Outcome::Ran);
+        assert!(net_mon.get_actions().await.is_empty());
+    }
+}
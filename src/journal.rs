@@ -0,0 +1,346 @@
+//! Append-only action journal.
+//!
+//! Every `ActionRecord` NetMon sees is written as one JSON line to
+//! `./safe/tmp/journal/<session_id>.jsonl` as soon as it is recorded, so a
+//! killed run still leaves a trail of what it finished. `ResumeState` reads
+//! that trail back for `run --resume <session_id>`.
+//!
+//! Writes and fsyncs happen on a thread of the journal's own, off the
+//! runtime: records queued while a sync runs are written and synced
+//! together, so a burst of actions costs one fsync, not one each.
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use anyhow::{Result, Context, bail};
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::net_mon::{Action, ActionRecord, NetMon};
+
+const JOURNAL_DIR: &str = "./safe/tmp/journal";
+
+/// Returns the journal path for a session.
+pub fn journal_path(session_id: Uuid) -> PathBuf {
+    Path::new(JOURNAL_DIR).join(format!("{}.jsonl", session_id))
+}
+
+pub struct Journal {
+    path: PathBuf,
+    /// Where this run's records start; a resumed session's earlier runs
+    /// come before.
+    start: u64,
+    /// Entries for the writer thread; `None` once the journal is dropped.
+    queue: Option<mpsc::Sender<Entry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+enum Entry {
+    Line(String),
+    /// Answered once every line queued before it is on disk.
+    Flush(mpsc::Sender<()>),
+}
+
+impl Journal {
+    /// Opens (or creates) the journal for `session_id` in append mode.
+    pub fn open(session_id: Uuid) -> Result<Self> {
+        Self::open_at(journal_path(session_id))
+    }
+
+    pub(crate) fn open_at(path: PathBuf) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open journal: {}", path.display()))?;
+        // A resumed session appends here; a torn line would end up mid-file.
+        truncate_torn_line(&mut file).with_context(|| format!("Failed to repair journal: {}", path.display()))?;
+        let start = file.metadata()?.len();
+
+        let (queue, entries) = mpsc::channel::<Entry>();
+        let writer_path = path.clone();
+        let writer = std::thread::Builder::new()
+            .name("journal".to_string())
+            .spawn(move || write_lines(file, &writer_path, entries))
+            .context("Failed to start journal thread")?;
+        Ok(Self { path, start, queue: Some(queue), writer: Some(writer) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queues one record for the writer thread, which syncs it to disk
+    /// with whatever else is queued. Does not block.
+    pub fn append(&self, record: &ActionRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)
+            .context("Failed to serialize journal record")?;
+        line.push('\n');
+        self.queue(Entry::Line(line))
+    }
+
+    /// Every record appended since the journal was opened, read back from
+    /// disk once the queued ones are written. Blocking.
+    pub fn records(&self) -> Result<Vec<ActionRecord>> {
+        let (done, written) = mpsc::channel();
+        self.queue(Entry::Flush(done))?;
+        written.recv().ok().context("Journal thread has stopped")?;
+
+        let mut file = File::open(&self.path)
+            .with_context(|| format!("Failed to open journal: {}", self.path.display()))?;
+        file.seek(SeekFrom::Start(self.start))?;
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.context("Failed to read journal")?;
+            let record = serde_json::from_str(&line)
+                .with_context(|| format!("Not a journal record: {}", self.path.display()))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn queue(&self, entry: Entry) -> Result<()> {
+        let queue = self.queue.as_ref().context("Journal is closed")?;
+        queue.send(entry).ok().context("Journal thread has stopped")?;
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    /// Waits for the writer to sync what is still queued.
+    fn drop(&mut self) {
+        drop(self.queue.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::error!("Journal thread panicked: {}", self.path.display());
+            }
+        }
+    }
+}
+
+/// The writer thread: writes each batch of queued lines, then syncs once.
+fn write_lines(mut file: File, path: &Path, entries: mpsc::Receiver<Entry>) {
+    while let Ok(first) = entries.recv() {
+        let mut batch = String::new();
+        let mut flushes = Vec::new();
+        for entry in std::iter::once(first).chain(entries.try_iter()) {
+            match entry {
+                Entry::Line(line) => batch.push_str(&line),
+                Entry::Flush(done) => flushes.push(done),
+            }
+        }
+        if !batch.is_empty() {
+            let written = file.write_all(batch.as_bytes()).and_then(|_| file.sync_data());
+            if let Err(e) = written {
+                log::error!("Journal write failed: {}: {}", path.display(), e);
+            }
+        }
+        for done in flushes {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Cuts a last line with no newline (the run was killed mid-write) off the
+/// file.
+fn truncate_torn_line(file: &mut File) -> std::io::Result<()> {
+    let mut end = file.metadata()?.len();
+    let mut buf = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|&b| b == b'\n') {
+            end = start + newline as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if end < file.metadata()?.len() {
+        warn!("Dropping a torn last line from the journal");
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+/// What a previous run of the same session already got done.
+#[derive(Debug, Default)]
+pub struct ResumeState {
+    completed: HashSet<String>,
+    /// Files whose secure overwrite started but never reached deletion,
+    /// mapped to the last pass that finished (0 if none did).
+    partial: HashMap<String, usize>,
+}
+
+impl ResumeState {
+    /// Rebuilds the state from the session journal. A torn last line (the
+    /// run was killed mid-write) is ignored; any other line that does not
+    /// parse fails the load, as the state would be missing records.
+    pub fn load(session_id: Uuid) -> Result<Self> {
+        let path = journal_path(session_id);
+        let file = File::open(&path)
+            .with_context(|| format!("No journal for session {} at {}", session_id, path.display()))?;
+        Self::read(BufReader::new(file)).with_context(|| format!("Bad journal: {}", path.display()))
+    }
+
+    fn read(reader: impl BufRead) -> Result<Self> {
+        let mut state = Self::default();
+        let mut lines = reader.lines().enumerate().peekable();
+        while let Some((index, line)) = lines.next() {
+            let line = line.context("Failed to read journal")?;
+            match serde_json::from_str::<ActionRecord>(&line) {
+                Ok(record) => state.apply(&record.action),
+                Err(_) if lines.peek().is_none() => warn!("Ignoring torn last journal line {}", index + 1),
+                Err(e) => bail!("Line {} is not a journal record: {}", index + 1, e),
+            }
+        }
+        Ok(state)
+    }
+
+    fn apply(&mut self, action: &Action) {
+        match action {
+            Action::OverwriteStarted(path) => {
+                self.partial.insert(path.clone(), 0);
+            }
+            Action::OverwritePass(path, pass, _) => {
+                self.partial.insert(path.clone(), *pass);
+            }
+            _ => {
+                if let Some(target) = action.completed_target() {
+                    self.partial.remove(target);
+                    self.completed.insert(target.to_string());
+                }
+            }
+        }
+    }
+
+    pub fn is_completed(&self, target: &str) -> bool {
+        self.completed.contains(target)
+    }
+
+    /// Last finished overwrite pass for a file that was interrupted mid-way.
+    pub fn partial_pass(&self, path: &str) -> Option<usize> {
+        self.partial.get(path).copied()
+    }
+}
+
+/// Sends `Action::Skipped` for a target a resumed session already finished.
+/// Returns true when the caller should skip it.
+pub async fn skip_completed(net_mon: &NetMon, resume: &ResumeState, target: &str) -> Result<bool> {
+    if !resume.is_completed(target) {
+        return Ok(false);
+    }
+    info!("Skipping {}: completed in resumed session", target);
+    net_mon.get_sender().send(Action::Skipped(format!("{}: already completed", target))).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn line(action: Action) -> String {
+        let record = ActionRecord { timestamp: Utc::now(), task: None, user: None, action };
+        serde_json::to_string(&record).unwrap()
+    }
+
+    #[test]
+    fn torn_last_line_is_ignored() {
+        let done = line(Action::FileDeleted("/tmp/a".to_string()));
+        let started = line(Action::OverwriteStarted("/tmp/b".to_string()));
+        let pass = line(Action::OverwritePass("/tmp/b".to_string(), 2, 10));
+        let torn = &line(Action::FileDeleted("/tmp/b".to_string()))[..20];
+        let journal = format!("{}\n{}\n{}\n{}", done, started, pass, torn);
+
+        let state = ResumeState::read(journal.as_bytes()).unwrap();
+        assert!(state.is_completed("/tmp/a"));
+        assert!(!state.is_completed("/tmp/b"));
+        assert_eq!(state.partial_pass("/tmp/b"), Some(2));
+    }
+
+    #[test]
+    fn bad_line_before_the_end_fails() {
+        let done = line(Action::FileDeleted("/tmp/a".to_string()));
+        let journal = format!("{}\nnot json\n{}\n", done, done);
+        let error = ResumeState::read(journal.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("Line 2"), "{}", error);
+    }
+
+    #[test]
+    fn finished_target_clears_partial_overwrite() {
+        let journal = [
+            line(Action::OverwriteStarted("/tmp/c".to_string())),
+            line(Action::OverwritePass("/tmp/c".to_string(), 1, 10)),
+            line(Action::FileDeleted("/tmp/c".to_string())),
+        ]
+        .join("\n");
+        let state = ResumeState::read(journal.as_bytes()).unwrap();
+        assert!(state.is_completed("/tmp/c"));
+        assert_eq!(state.partial_pass("/tmp/c"), None);
+    }
+
+    #[test]
+    fn truncates_torn_line() {
+        let path = std::env::temp_dir().join(format!("journal-test-{}.jsonl", Uuid::new_v4()));
+        let cases: [(&[u8], &[u8]); 4] = [
+            (b"{\"a\":1}\n{\"b\"", b"{\"a\":1}\n"),
+            (b"{\"a\":1}\n", b"{\"a\":1}\n"),
+            (b"{\"torn", b""),
+            (b"", b""),
+        ];
+        for (contents, expected) in cases {
+            fs::write(&path, contents).unwrap();
+            let mut file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+            truncate_torn_line(&mut file).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), expected);
+        }
+        // Lines longer than the read buffer.
+        let long = format!("{}\n{}", "x".repeat(10_000), "y".repeat(9_000));
+        fs::write(&path, &long).unwrap();
+        let mut file = OpenOptions::new().read(true).append(true).open(&path).unwrap();
+        truncate_torn_line(&mut file).unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), 10_001);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn records_are_those_of_this_run() {
+        let path = std::env::temp_dir().join(format!("journal-test-{}.jsonl", Uuid::new_v4()));
+        let earlier = line(Action::FileDeleted("/tmp/earlier".to_string()));
+        fs::write(&path, format!("{}\n{{\"torn", earlier)).unwrap();
+
+        let journal = Journal::open_at(path.clone()).unwrap();
+        let record = |target: &str| ActionRecord { timestamp: Utc::now(), task: None, user: None, action: Action::FileDeleted(target.to_string()) };
+        journal.append(&record("/tmp/a")).unwrap();
+        journal.append(&record("/tmp/b")).unwrap();
+        let targets: Vec<String> = journal.records().unwrap().into_iter().map(|r| r.action.detail()).collect();
+        assert_eq!(targets, ["/tmp/a", "/tmp/b"]);
+        drop(journal);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn net_mon_reads_evicted_records_back() {
+        let path = std::env::temp_dir().join(format!("journal-test-{}.jsonl", Uuid::new_v4()));
+        let net_mon = NetMon::with_journal(Journal::open_at(path.clone()).unwrap()).with_capacity(2);
+        net_mon.start_monitoring().await;
+        for i in 0..5 {
+            net_mon.get_sender().send(Action::FileDeleted(format!("/tmp/{}", i))).await.unwrap();
+        }
+        net_mon.close().await;
+        assert_eq!(net_mon.get_stats().await.spilled, 3);
+        let targets: Vec<String> = net_mon.get_actions().await.into_iter().map(|r| r.action.detail()).collect();
+        assert_eq!(targets, ["/tmp/0", "/tmp/1", "/tmp/2", "/tmp/3", "/tmp/4"]);
+        drop(net_mon);
+        fs::remove_file(&path).unwrap();
+    }
+}
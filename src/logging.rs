@@ -0,0 +1,184 @@
+//! Logger setup from `LogConfig`: a plain-text file (optionally rolling by
+//! size and/or age), an optional JSON-structured file, optional stderr
+//! output and per-module levels. A log4rs YAML file replaces all of it.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use anyhow::{Result, Context};
+use log::LevelFilter;
+use log4rs::{
+    append::{
+        console::{ConsoleAppender, Target},
+        file::FileAppender,
+        rolling_file::{
+            policy::compound::{roll::fixed_window::FixedWindowRoller, trigger::Trigger, CompoundPolicy},
+            LogFile, RollingFileAppender,
+        },
+        Append,
+    },
+    config::{Appender, Config, Logger, Root},
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
+};
+use parking_lot::Mutex;
+use serde::{Serialize, Deserialize};
+
+const PATTERN: &str = "{d} - {l} - {m}\n";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Root level: off, error, warn, info, debug or trace.
+    pub level: String,
+    /// Level overrides by module path, e.g. `"cleanCleanCleaner::net_mon": "debug"`.
+    pub modules: BTreeMap<String, String>,
+    /// Plain-text log file; `null` disables it.
+    pub file: Option<LogFileConfig>,
+    /// JSON-structured log file, one object per line.
+    pub json_file: Option<LogFileConfig>,
+    pub stderr: bool,
+    /// A log4rs YAML file to use instead of everything above.
+    pub log4rs_yaml: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            file: Some(LogFileConfig::new("./safe/tmp/cleanup.log")),
+            json_file: None,
+            stderr: false,
+            log4rs_yaml: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Roll over once the file reaches this size.
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    /// Roll over once the file is this old.
+    #[serde(default)]
+    pub max_age_hours: Option<u64>,
+    /// Rolled files to keep (`<path>.1` ... `<path>.<keep>`).
+    #[serde(default = "default_keep")]
+    pub keep: u32,
+}
+
+fn default_keep() -> u32 {
+    5
+}
+
+impl LogFileConfig {
+    fn new(path: &str) -> Self {
+        Self { path: PathBuf::from(path), max_size_mb: None, max_age_hours: None, keep: default_keep() }
+    }
+
+    fn build(&self, encoder: Box<dyn Encode>) -> Result<Box<dyn Append>> {
+        if self.max_size_mb.is_none() && self.max_age_hours.is_none() {
+            let appender = FileAppender::builder()
+                .encoder(encoder)
+                .build(&self.path)
+                .with_context(|| format!("Failed to create log file: {}", self.path.display()))?;
+            return Ok(Box::new(appender));
+        }
+
+        let trigger = SizeOrAgeTrigger {
+            max_size: self.max_size_mb.map(|mb| mb * 1024 * 1024),
+            max_age: self.max_age_hours.map(|h| Duration::from_secs(h * 3600)),
+            opened_at: Mutex::new(None),
+        };
+        let roller = FixedWindowRoller::builder()
+            .build(&format!("{}.{{}}", self.path.display()), self.keep.max(1))
+            .context("Failed to configure log roller")?;
+        let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+        let appender = RollingFileAppender::builder()
+            .encoder(encoder)
+            .build(&self.path, Box::new(policy))
+            .with_context(|| format!("Failed to create log file: {}", self.path.display()))?;
+        Ok(Box::new(appender))
+    }
+}
+
+/// Rolls when the active file passes `max_size` or `max_age`, whichever
+/// comes first.
+#[derive(Debug)]
+struct SizeOrAgeTrigger {
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    opened_at: Mutex<Option<SystemTime>>,
+}
+
+impl Trigger for SizeOrAgeTrigger {
+    fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+        if self.max_size.is_some_and(|max| file.len_estimate() > max) {
+            *self.opened_at.lock() = Some(SystemTime::now());
+            return Ok(true);
+        }
+        let Some(max_age) = self.max_age else { return Ok(false) };
+
+        let mut opened_at = self.opened_at.lock();
+        let opened = *opened_at.get_or_insert_with(|| file_birth(file.path()));
+        let expired = opened.elapsed().is_ok_and(|age| age > max_age);
+        if expired {
+            *opened_at = Some(SystemTime::now());
+        }
+        Ok(expired)
+    }
+
+    fn is_pre_process(&self) -> bool {
+        false
+    }
+}
+
+fn file_birth(path: &Path) -> SystemTime {
+    std::fs::metadata(path)
+        .and_then(|m| m.created().or_else(|_| m.modified()))
+        .unwrap_or_else(|_| SystemTime::now())
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| anyhow::anyhow!("Invalid log level: {}", level))
+}
+
+pub fn setup_logging(config: &LogConfig) -> Result<()> {
+    if let Some(yaml) = &config.log4rs_yaml {
+        return log4rs::init_file(yaml, Default::default())
+            .with_context(|| format!("Failed to initialize logger from {}", yaml.display()));
+    }
+
+    let mut builder = Config::builder();
+    let mut root = Root::builder();
+
+    if let Some(file) = &config.file {
+        let appender = file.build(Box::new(PatternEncoder::new(PATTERN)))?;
+        builder = builder.appender(Appender::builder().build("logfile", appender));
+        root = root.appender("logfile");
+    }
+    if let Some(file) = &config.json_file {
+        let appender = file.build(Box::new(JsonEncoder::new()))?;
+        builder = builder.appender(Appender::builder().build("jsonfile", appender));
+        root = root.appender("jsonfile");
+    }
+    if config.stderr {
+        let stderr = ConsoleAppender::builder()
+            .target(Target::Stderr)
+            .encoder(Box::new(PatternEncoder::new(PATTERN)))
+            .build();
+        builder = builder.appender(Appender::builder().build("stderr", Box::new(stderr)));
+        root = root.appender("stderr");
+    }
+    for (module, level) in &config.modules {
+        builder = builder.logger(Logger::builder().build(module, parse_level(level)?));
+    }
+
+    let config = builder
+        .build(root.build(parse_level(&config.level)?))
+        .context("Failed to configure logger")?;
+
+    log4rs::init_config(config).context("Failed to initialize logger")?;
+    Ok(())
+}
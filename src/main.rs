@@ -1,17 +1,41 @@
+mod audit;
+mod caches;
+mod cli;
+mod config;
+mod core_dumps;
+mod daemon;
+mod docker;
+mod homes;
+mod hooks;
+mod journal;
+mod logging;
 mod net_mon;
+mod open_files;
+mod plan;
+mod privileges;
+mod prune;
+mod report;
+mod report_diff;
+mod review;
+mod rotated_logs;
+mod scan;
+mod schedule;
+mod session;
+mod stale_files;
+mod summary;
+mod supervise;
+mod trash;
 mod utils;
 
 use tokio;
-use log::{info, error, LevelFilter};
-use log4rs::{
-    append::file::FileAppender,
-    config::{Appender, Config, Root},
-    encode::pattern::PatternEncoder,
-};
+use log::{info, warn, error};
 use uuid::Uuid;
-use anyhow::{Result, Context};
-use futures::future::join_all;
-use std::path::Path;
+use anyhow::{Result, Context, bail};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::future::Future;
+use clap::Parser;
 use std::io::{SeekFrom, seek};
 use tokio::time::Instant
 use tokio::fs;
@@ -22,93 +46,451 @@ use filetime::{FileTime, from_unix_time};
 use filetime::set_file_mtime;
 
 
-use crate::net_mon::{ NetMon, get_actions, Action };
-use crate::utils::save_to_json;
+use crate::audit::AuditSink;
+use crate::caches::clean_cache;
+use crate::docker::clean_docker;
+use crate::homes::clean_user_homes;
+use crate::cli::{Cli, Commands, ReportCommand, RunArgs, ScanArgs};
+use crate::config::Config;
+use crate::core_dumps::clean_core_dumps;
+use crate::daemon::run_daemon;
+use crate::hooks::{with_hooks, Outcome};
+use crate::logging::setup_logging;
+use crate::journal::{Journal, ResumeState, skip_completed};
+use crate::net_mon::{ NetMon, get_actions, Action, in_task };
+use crate::open_files::{open_files, open_under, OnOpen};
+use crate::plan::{Plan, PlanTask, TaskKind};
+use crate::privileges::{check_plan, held_until_drop, lookup_user, require_privileges, DroppedPrivileges, Privileges, User};
+use crate::prune::{prune_empty_dirs, remove_dangling_symlinks};
+use crate::report::{write_report, ReportFormat};
+use crate::report_diff::diff_reports;
+use crate::review::{confirm, review_plan, reviewed_dry_run};
+use crate::rotated_logs::clean_rotated_logs;
+use crate::scan::scan;
+use crate::session::{Session, SessionStatus};
+use crate::stale_files::clean_stale_files;
+use crate::summary::{RunSummary, UserReport};
+use crate::supervise::{cancel_on_signal, is_cancelled, supervise, CancelToken};
+use crate::trash::clean_trash;
+use crate::utils::{save_to_json, dir_size, remove_tree_except, write_atomic};
 //use tokio::task;
 //use tokio::sync::mpsc;
 //use filetime::FileTime;
 use anyhow::Result;
 
-#[derive(Debug)]
-struct ConfigTime {
-    session_id: Uuid,
-    uuid: Uuid,
-    timestamp: Instant,
-    action: Action,
+#[tokio::main]
+async fn main() -> Result<()> {
+    
+    let cli = Cli::parse();
+    let mut config = match &cli.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(yaml) = cli.log4rs {
+        config.logging.log4rs_yaml = Some(yaml);
+    }
+    setup_logging(&config.logging)?;
+
+    let args = match cli.command {
+        Some(Commands::Run(args)) => args,
+        Some(Commands::Daemon(args)) => return run_daemon(&config, args).await,
+        Some(Commands::Scan(args)) => return run_scan(args).await,
+        Some(Commands::Report { command }) => return run_report_command(command),
+        None => RunArgs::default(),
+    };
+
+    info!("Starting Concurrent Advanced Cleanup...");
+
+    let plan = match &args.plan {
+        Some(path) => Plan::load(path)?,
+        None => Plan::builtin(),
+    };
+    let drop_to = args.drop_privileges.as_deref().map(lookup_user).transpose()?;
+    if drop_to.is_some() {
+        if Privileges::current().euid != 0 {
+            bail!("--drop-privileges needs root");
+        }
+        held_until_drop(&plan)?;
+    }
+    check_plan(&plan.tasks);
+    let declined = approve_run(&plan, &args).await?;
+    let shutdown = CancelToken::new();
+    cancel_on_signal(&shutdown)?;
+    let run = SessionRun::start(&config, &plan, &shutdown, args.resume, args.dry_run, args.log_capacity).await?;
+
+    let task_failed = run_plan(&run, &plan, args.dry_run, &declined, drop_to.as_ref()).await?;
+
+    let summary = run.finish(task_failed, &args.formats).await?;
+    println!("{}", summary);
+
+    Ok(())
 }
 
-impl ConfigTime {
-    fn new_action(action: Action) -> Self {
-        Self {
-            session_id: Uuid::new_v4(),
-            uuid: Uuid::new_v4(),
-            timestamp: Instant::now(),
-            action,
+/// One cleanup session: its header, journal, NetMon and resume state,
+/// from start to the reports written at the end.
+struct SessionRun {
+    session: Session,
+    net_mon: NetMon,
+    resume: ResumeState,
+    /// Cancelled with the process; the parent of every task's token.
+    cancel: CancelToken,
+    started: Instant,
+}
+
+impl SessionRun {
+    async fn start(
+        config: &Config,
+        plan: &Plan,
+        shutdown: &CancelToken,
+        resume_id: Option<Uuid>,
+        dry_run: bool,
+        log_capacity: usize,
+    ) -> Result<Self> {
+        let mut session = Session::start(resume_id, plan.hash());
+        session.dry_run = dry_run;
+        if dry_run {
+            info!("Dry run: sizing up targets, nothing will be changed");
+        }
+        let session_id: Uuid = session.id;
+        info!("Session {} on {} as {}", session_id, session.host.hostname, session.user);
+        let started: Instant = tokio::time::Instant::now();
+
+        let resume = match resume_id {
+            Some(id) => {
+                info!("Resuming session {}", id);
+                ResumeState::load(id)?
+            }
+            None => ResumeState::default(),
+        };
+
+        let journal = Journal::open(session_id)?;
+        info!("Journaling actions to {}", journal.path().display());
+        let mut net_mon = NetMon::with_journal(journal).with_capacity(log_capacity);
+        if let Some(audit) = &config.audit {
+            net_mon = net_mon.with_audit(AuditSink::connect(audit, session_id)?);
+        }
+        net_mon.start_monitoring().await;
+
+        Ok(Self { session, net_mon, resume, cancel: shutdown.child(), started })
+    }
+
+    /// Closes the session and writes its reports and summary.
+    async fn finish(self, task_failed: bool, formats: &[ReportFormat]) -> Result<RunSummary> {
+        let SessionRun { mut session, net_mon, cancel, started, .. } = self;
+        let duration = started.elapsed();
+        info!("Concurrent Advanced Cleanup completed in {:?}", duration);
+
+        // The last task's actions may still be queued.
+        net_mon.close().await;
+        let stats = net_mon.get_stats().await;
+        if stats.spilled > 0 {
+            info!("{} of {} records were evicted from memory; reading them back from the journal", stats.spilled, stats.total);
         }
+        let actions = net_mon.get_actions().await;
+
+        session.finish(if cancel.is_cancelled() {
+            SessionStatus::Cancelled
+        } else if task_failed || !stats.errors_by_kind.is_empty() {
+            SessionStatus::CompletedWithErrors
+        } else {
+            SessionStatus::Completed
+        });
+        let session_id = session.id;
+        let summary = RunSummary::new(session, duration, &stats);
+        for format in formats {
+            write_report(*format, &actions, &summary, "cleanup_actions")?;
+        }
+        save_to_json(&summary, "cleanup_summary", session_id)?;
+        for user in summary.by_user.keys() {
+            save_to_json(&UserReport::new(&summary, user, &actions), &format!("user_report_{}", user), session_id)?;
+        }
+        Ok(summary)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    
-    setup_logging()?;
-    info!("Starting Concurrent Advanced Cleanup...");
+/// Gets the go-ahead for a run that changes things: `--yes` with a plan
+/// that had a dry run, or the review prompt. Returns the plan indices of
+/// the tasks not to run.
+async fn approve_run(plan: &Plan, args: &RunArgs) -> Result<HashSet<usize>> {
+    if args.dry_run {
+        return Ok(HashSet::new());
+    }
+    let plan_hash = plan.hash();
+    if args.yes {
+        match reviewed_dry_run(&plan_hash)? {
+            Some(session_id) => info!("Plan {} had dry run {}, running without review", plan_hash, session_id),
+            None => bail!("Plan {} has no completed dry run; run it with --dry-run first", plan_hash),
+        }
+        return Ok(HashSet::new());
+    }
 
-    let session: ConfigTime = ConfigTime::new_action(Action);
-    let session_id: Uuid = session.session_id;
-    let start_time: Instant = tokio::time::Instant::now();
+    let resume = match args.resume {
+        Some(id) => ResumeState::load(id)?,
+        None => ResumeState::default(),
+    };
+    let reviews = review_plan(plan, &resume).await?;
+    let plan = plan.clone();
+    tokio::task::spawn_blocking(move || confirm(&reviews, &plan)).await?
+}
 
-    let net_mon = NetMon::new();
-    net_mon.start_monitoring().await;
+async fn run_scan(args: ScanArgs) -> Result<()> {
+    info!("Scanning {}...", args.path.display());
+    let result = scan(&args.path, args.top, args.min_size * 1024 * 1024, args.jobs).await?;
+    print!("{}", result);
 
-    let basic_tasks = vec![
-        tokio::spawn(remove_temp_files(&net_mon)),
-        tokio::spawn(clear_bash_history(&net_mon)),
-        tokio::spawn(update_timestamps(&net_mon)),
-    ];
+    let plan = result.plan();
+    plan.validate()?;
+    write_atomic(&args.output, serde_json::to_string_pretty(&plan)?.as_bytes())?;
+    println!("\nSuggested plan written to {}; review it, then `run --plan {} --dry-run`", args.output.display(), args.output.display());
+    Ok(())
+}
 
-    let advanced_tasks = vec![
-        tokio::spawn(secure_delete_file(&net_mon, "./safe/tmp/sensitive_data.txt")),
-        tokio::spawn(manipulate_log_file(&net_mon, "./safe/var/log/system.log")),
-        tokio::spawn(remove_network_traces(&net_mon)),
-    ];
+fn run_report_command(command: ReportCommand) -> Result<()> {
+    match command {
+        ReportCommand::Diff { old, new, json } => {
+            let diff = diff_reports(&old, &new)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print!("{}", diff);
+            }
+        }
+    }
+    Ok(())
+}
 
-    let all_tasks: Vec<_> = [basic_tasks, advanced_tasks].into_iter().flatten().collect();
-    let results: Vec<Result<Result<()>,anyhow::Result<()>> = join_all.iter(all_tasks).await;
+/// Runs the plan's tasks as a graph: each starts as soon as every task it
+/// depends on has succeeded, so independent tasks run concurrently.
+/// Dependents of a failed task don't run and get `DependencyFailed`;
+/// `declined` tasks (and so their dependents) are recorded as `Skipped`.
+/// With `drop_to`, tasks that need elevated rights and their dependencies
+/// run first, then the rest as that user. Once the run is cancelled no
+/// more tasks start (nor their hooks, nor the user switch); those left
+/// are recorded as `Cancelled`. Returns whether any task failed.
+async fn run_plan(run: &SessionRun, plan: &Plan, dry_run: bool, declined: &HashSet<usize>, drop_to: Option<&User>) -> Result<bool> {
+    let (net_mon, resume, cancel) = (&run.net_mon, &run.resume, &run.cancel);
+    let deps = plan.dependencies()?;
+    let mut waiting: Vec<usize> = deps.iter().map(Vec::len).collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); plan.tasks.len()];
+    for (i, dep) in deps.iter().enumerate() {
+        for &d in dep {
+            dependents[d].push(i);
+        }
+    }
 
-    for result in results {
-        if let Err(e) = result {
-            error!("Task error: {}", e);
+    let start = |i: usize| {
+        let task = &plan.tasks[i];
+        async move {
+            let supervised = supervise(net_mon, task, cancel, || run_task(net_mon, resume, task, dry_run));
+            let result = timed(net_mon, &task.name, async {
+                require_privileges(net_mon, task).await?;
+                with_hooks(net_mon, plan, task, cancel, dry_run, supervised).await
+            })
+            .await;
+            (i, result)
+        }
+    };
+    let mut skipped: Vec<usize> = declined.iter().copied().collect();
+    skipped.sort_unstable();
+    for i in skipped {
+        info!("Skipping declined task {}", plan.tasks[i].name);
+        net_mon.get_sender().send(Action::Skipped(plan.tasks[i].name.clone())).await?;
+    }
+    let mut blocked: Vec<bool> = (0..plan.tasks.len()).map(|i| declined.contains(&i)).collect();
+    let mut held = match drop_to {
+        Some(_) => held_until_drop(plan)?,
+        None => vec![false; plan.tasks.len()],
+    };
+    let mut started = vec![false; plan.tasks.len()];
+    let mut running = FuturesUnordered::new();
+    if !cancel.is_cancelled() {
+        for i in (0..plan.tasks.len()).filter(|&i| waiting[i] == 0 && !blocked[i] && !held[i]) {
+            started[i] = true;
+            running.push(start(i));
         }
     }
+    let mut task_failed = false;
+    // Restores the identity when the plan is done, before the reports.
+    let mut _dropped = None;
+
+    loop {
+        while let Some((i, result)) = running.next().await {
+            let name = &plan.tasks[i].name;
+            // Whether the task failed, if it didn't run through.
+            let stopped = match result {
+                Ok(Outcome::Ran) => None,
+                Ok(Outcome::Skipped) => Some(false),
+                Err(e) => {
+                    error!("Task {} failed: {:#}", name, e);
+                    task_failed = true;
+                    Some(true)
+                }
+            };
+            if let Some(failed) = stopped {
+                let mut stack = dependents[i].clone();
+                while let Some(d) = stack.pop() {
+                    if std::mem::replace(&mut blocked[d], true) {
+                        continue;
+                    }
+                    let dependent = plan.tasks[d].name.clone();
+                    if failed {
+                        warn!("Not running {}: {} failed", dependent, name);
+                        net_mon.get_sender().send(Action::DependencyFailed(dependent, name.clone())).await?;
+                    } else {
+                        info!("Skipping {}: {} was skipped", dependent, name);
+                        net_mon.get_sender().send(Action::Skipped(format!("{}: runs after skipped {}", dependent, name))).await?;
+                    }
+                    stack.extend(dependents[d].iter().copied());
+                }
+                continue;
+            }
+            for &d in &dependents[i] {
+                waiting[d] -= 1;
+                if waiting[d] == 0 && !blocked[d] && !held[d] && !cancel.is_cancelled() {
+                    started[d] = true;
+                    running.push(start(d));
+                }
+            }
+        }
 
-    let actions = net_mon.get_actions().await;
-    save_to_json(&actions, &format!("cleanup_actions_{}", session_id))?;
+        // The privileged tasks are done; switch user and run the rest.
+        let Some(user) = drop_to.filter(|_| held.contains(&true) && !cancel.is_cancelled()) else { break };
+        match DroppedPrivileges::switch_to(user) {
+            Ok(dropped) => _dropped = Some(dropped),
+            Err(e) => {
+                error!("Not running the remaining tasks: {:#}", e);
+                net_mon.get_sender().send(Action::Error(format!("{:#}", e))).await?;
+                task_failed = true;
+                break;
+            }
+        }
+        let released: Vec<usize> = (0..plan.tasks.len()).filter(|&i| held[i]).collect();
+        held.fill(false);
+        for i in released.into_iter().filter(|&i| waiting[i] == 0 && !blocked[i]) {
+            started[i] = true;
+            running.push(start(i));
+        }
+    }
 
-    let duration = start_time.elapsed();
-    info!("Concurrent Advanced Cleanup completed in {:?}", duration);
+    if cancel.is_cancelled() {
+        for task in (0..plan.tasks.len()).filter(|&i| !started[i] && !blocked[i]).map(|i| &plan.tasks[i]) {
+            warn!("Not starting {}: run cancelled", task.name);
+            let cancelled = Action::Cancelled(task.name.clone(), "run cancelled before it started".to_string());
+            in_task(&task.name, net_mon.get_sender().send(cancelled)).await?;
+        }
+    }
+    Ok(task_failed)
+}
 
-    Ok(())
+/// Runs one task under its name and records its wall time as
+/// `Action::TaskFinished`.
+async fn timed<F, T>(net_mon: &NetMon, name: &str, task: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let started = Instant::now();
+    let result = in_task(name, task).await;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    net_mon.get_sender().send(Action::TaskFinished(name.to_string(), elapsed_ms)).await?;
+    result
 }
 
-async fn remove_temp_files(net_mon: &NetMon) -> Result<()> {
+/// Runs one plan task. In a dry run, tasks with fixed targets only report
+/// the size of each target; tasks that discover targets size those up.
+async fn run_task(net_mon: &NetMon, resume: &ResumeState, task: &PlanTask, dry_run: bool) -> Result<()> {
+    if dry_run && !task.kind.discovers_targets() {
+        for target in task.kind.targets() {
+            let path = target.clone();
+            let size = tokio::task::spawn_blocking(move || dir_size(Path::new(&path))).await?;
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+        }
+        return Ok(());
+    }
+
+    match &task.kind {
+        TaskKind::RemoveTempFiles { path, on_open } => remove_temp_files(net_mon, resume, path, *on_open).await,
+        TaskKind::ClearBashHistory => clear_bash_history(net_mon, resume).await,
+        TaskKind::UpdateTimestamps { files } => update_timestamps(net_mon, resume, files).await,
+        TaskKind::SecureDeleteFile { path } => secure_delete_file(net_mon, resume, path).await,
+        TaskKind::ManipulateLogFile { path } => manipulate_log_file(net_mon, resume, path).await,
+        TaskKind::RemoveNetworkTraces => remove_network_traces(net_mon, resume).await,
+        TaskKind::Cache(cache) => clean_cache(net_mon, resume, cache, dry_run).await,
+        TaskKind::Docker(docker) => clean_docker(net_mon, resume, docker, dry_run).await,
+        TaskKind::RotatedLogs(logs) => clean_rotated_logs(net_mon, resume, logs, dry_run).await,
+        TaskKind::UserHomes(homes) => clean_user_homes(net_mon, resume, homes, dry_run).await,
+        TaskKind::Trash(trash) => clean_trash(net_mon, resume, trash, dry_run).await,
+        TaskKind::StaleFiles(stale) => clean_stale_files(net_mon, resume, stale, dry_run).await,
+        TaskKind::CoreDumps(dumps) => clean_core_dumps(net_mon, resume, dumps, dry_run).await,
+        TaskKind::EmptyDirs(dirs) => prune_empty_dirs(net_mon, resume, dirs, dry_run).await,
+        TaskKind::DanglingSymlinks(links) => remove_dangling_symlinks(net_mon, resume, links, dry_run).await,
+    }
+}
+
+async fn remove_temp_files(net_mon: &NetMon, resume: &ResumeState, temp_dir: &str, on_open: OnOpen) -> Result<()> {
     info!("Removing temporary files...");
-    let temp_dir = "/tmp/cleancleanclean";
+    if skip_completed(net_mon, resume, temp_dir).await? {
+        return Ok(());
+    }
+    let started = Instant::now();
+    let root = fs::canonicalize(temp_dir).await.unwrap_or_else(|_| PathBuf::from(temp_dir));
+    let scan_root = root.clone();
+    let held = tokio::task::spawn_blocking(move || {
+        let open = open_files();
+        open_under(&open, &scan_root)
+            .into_iter()
+            .map(|(file, holders)| (file.to_path_buf(), holders.to_vec()))
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    if !held.is_empty() {
+        for (file, holders) in &held {
+            for holder in holders {
+                warn!("{} is open in pid {} ({}), leaving it", file.display(), holder.pid, holder.command);
+                net_mon.get_sender().send(Action::FileInUse(file.display().to_string(), holder.pid, holder.command.clone())).await?;
+            }
+        }
+        if on_open == OnOpen::SkipTarget {
+            info!("Leaving {}: {} files in it are open", temp_dir, held.len());
+            return Ok(());
+        }
+        let keep: HashSet<PathBuf> = held.into_iter().map(|(file, _)| file).collect();
+        let tree = tokio::task::spawn_blocking(move || remove_tree_except(&root, &keep)).await?;
+        info!("Removed {} closed entries under {}, {} failed", tree.removed.len(), temp_dir, tree.failed.len());
+        for (path, size) in tree.removed {
+            let target = path.display().to_string();
+            net_mon.get_sender().send(Action::FileDeleted(target.clone())).await?;
+            net_mon.get_sender().send(Action::SpaceFreed(target, size)).await?;
+        }
+        for (path, reason) in tree.failed {
+            net_mon.get_sender().send(Action::TargetFailed(path.display().to_string(), reason)).await?;
+        }
+        net_mon.get_sender().send(Action::ItemTimed(temp_dir.to_string(), started.elapsed().as_millis() as u64)).await?;
+        return Ok(());
+    }
+
+    let path = temp_dir.to_string();
+    let size = tokio::task::spawn_blocking(move || dir_size(Path::new(&path))).await?;
     match fs::remove_dir_all(temp_dir).await {
         Ok(_) => {
             info!("Removed directory: {}", temp_dir);
             net_mon.get_sender().send(Action::FileDeleted(temp_dir.to_string())).await?;
+            net_mon.get_sender().send(Action::SpaceFreed(temp_dir.to_string(), size)).await?;
+            net_mon.get_sender().send(Action::ItemTimed(temp_dir.to_string(), started.elapsed().as_millis() as u64)).await?;
         },
         Err(e) => {
             error!("Error removing directory {}: {}", temp_dir, e);
-            net_mon.get_sender().send(Action::Error(format!("Failed to remove {}: {}", temp_dir, e))).await?;
+            net_mon.get_sender().send(Action::TargetFailed(temp_dir.to_string(), format!("Failed to remove: {}", e))).await?;
         }
     }
     Ok(())
 }
 
-async fn clear_bash_history(net_mon: &NetMon) -> Result<()> {
+async fn clear_bash_history(net_mon: &NetMon, resume: &ResumeState) -> Result<()> {
     info!("Clearing bash history...");
+    if skip_completed(net_mon, resume, "bash_history").await? {
+        return Ok(());
+    }
     let output = Command::new("bash")
         .arg("-c")
         .arg("history -c && history -w")
@@ -127,19 +509,56 @@ async fn clear_bash_history(net_mon: &NetMon) -> Result<()> {
     Ok(())
 }
 
-async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
+/// Ends a cancelled overwrite at a recorded point: what was written of
+/// `pass` is synced, and the journal says how much. The file is kept; a
+/// resumed session redoes every pass.
+async fn stop_overwrite(net_mon: &NetMon, file: &mut fs::File, path: &str, pass: usize, written: u64) -> Result<()> {
+    file.flush().await.context("Failed to flush file")?;
+    file.sync_all().await.context("Failed to sync file")?;
+    warn!("Overwrite of {} cancelled in pass {} after {} bytes", path, pass, written);
+    net_mon.get_sender().send(Action::OverwriteInterrupted(path.to_string(), pass, written)).await?;
+    Err(anyhow::anyhow!("Overwrite of {} cancelled", path))
+}
+
+/// Overwrites a file and removes it. A failure other than cancellation is
+/// recorded as `TargetFailed` before it is returned.
+async fn secure_delete_file(net_mon: &NetMon, resume: &ResumeState, path: &str) -> Result<()> {
+    let result = overwrite_and_remove(net_mon, resume, path).await;
+    if let Err(e) = &result {
+        if !is_cancelled() {
+            net_mon.get_sender().send(Action::TargetFailed(path.to_string(), format!("{:#}", e))).await?;
+        }
+    }
+    result
+}
+
+async fn overwrite_and_remove(net_mon: &NetMon, resume: &ResumeState, path: &str) -> Result<()> {
     info!("Initiating secure deletion of file: {}", path);
+    if skip_completed(net_mon, resume, path).await? {
+        return Ok(());
+    }
 
     let file_path = Path::new(path);
+
+    // A partial overwrite can't be trusted halfway: redo every pass.
+    if let Some(pass) = resume.partial_pass(path) {
+        if !file_path.exists() {
+            // Killed between the unlink and journaling it.
+            info!("{} already removed by the interrupted run", path);
+            net_mon.get_sender().send(Action::FileDeleted(path.to_string())).await?;
+            return Ok(());
+        }
+        info!("{} was interrupted after pass {}, restarting from pass one", path, pass);
+    }
     
     // Check if file exists
     if !file_path.exists() {
         let err_msg = format!("File not found: {}", path);
         error!("{}", err_msg);
-        net_mon.get_sender().send(Action::Error(err_msg.clone())).await?;
         return Err(anyhow::anyhow!(err_msg));
     }
 
+    let started = Instant::now();
     let file_size = file_path.metadata()
         .context("Failed to get file metadata")?
         .len();
@@ -150,6 +569,8 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
         .await
         .context("Failed to open file for secure deletion")?;
 
+    net_mon.get_sender().send(Action::OverwriteStarted(path.to_string())).await?;
+
     // Define overwrite patterns
     let patterns: [u8; 3] = [0x00, 0xFF, 0xAA];
 
@@ -163,6 +584,9 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
         let mut written = 0;
 
         while written < file_size {
+            if is_cancelled() {
+                return stop_overwrite(net_mon, &mut file, path, i + 1, written).await;
+            }
             let to_write = std::cmp::min(buffer.len() as u64, file_size - written) as usize;
             file.write_all(&buffer[..to_write]).await
                 .context("Failed to write during overwrite")?;
@@ -171,6 +595,7 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
 
         file.flush().await.context("Failed to flush file")?;
         file.sync_all().await.context("Failed to sync file")?;
+        net_mon.get_sender().send(Action::OverwritePass(path.to_string(), i + 1, file_size)).await?;
     }
 
     // Final pass with random data
@@ -183,6 +608,9 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
     let mut written = 0;
 
     while written < file_size {
+        if is_cancelled() {
+            return stop_overwrite(net_mon, &mut file, path, patterns.len() + 1, written).await;
+        }
         rng.fill_bytes(&mut buffer);
         let to_write = std::cmp::min(buffer.len() as u64, file_size - written) as usize;
         file.write_all(&buffer[..to_write]).await
@@ -192,6 +620,7 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
 
     file.flush().await.context("Failed to flush file after random overwrite")?;
     file.sync_all().await.context("Failed to sync file after random overwrite")?;
+    net_mon.get_sender().send(Action::OverwritePass(path.to_string(), patterns.len() + 1, file_size)).await?;
 
     // Close the file handle
     drop(file);
@@ -202,16 +631,21 @@ async fn secure_delete_file(net_mon: &NetMon, path: &str) -> Result<()> {
 
     info!("File securely deleted: {}", path);
     net_mon.get_sender().send(Action::FileDeleted(path.to_string())).await?;
+    net_mon.get_sender().send(Action::SpaceFreed(path.to_string(), file_size)).await?;
+    net_mon.get_sender().send(Action::ItemTimed(path.to_string(), started.elapsed().as_millis() as u64)).await?;
 
     Ok(())
 }
 
-async fn update_timestamps(net_mon: &NetMon) -> Result<()> {
+async fn update_timestamps(net_mon: &NetMon, resume: &ResumeState, files: &[String]) -> Result<()> {
     info!("Updating file timestamps...");
-    let files = vec!["/tmp/example1.txt", "/tmp/example2.txt"];
     let now = Local::now();
 
-    for &file_path in &files {
+    for file_path in files.iter().map(String::as_str) {
+        if skip_completed(net_mon, resume, file_path).await? {
+            continue;
+        }
+
         // Fetch metadata
         let metadata = fs::metadata(&file_path).await?;
         
@@ -233,7 +667,7 @@ async fn update_timestamps(net_mon: &NetMon) -> Result<()> {
             }
             Ok(Err(e)) | Err(e) => {
                 net_mon.get_sender()
-                    .send(Action::Error(format!("Failed to update timestamp for {}: {}", file_path, e)))
+                    .send(Action::TargetFailed(file_path.to_string(), format!("Failed to update timestamp: {}", e)))
                     .await?;
             }
         }
@@ -246,8 +680,11 @@ use chrono::Local;
 use anyhow::{Result, Context};
 use log::{info, warn};
 
-async fn manipulate_log_file(net_mon: &NetMon, log_path: &str) -> Result<()> {
+async fn manipulate_log_file(net_mon: &NetMon, resume: &ResumeState, log_path: &str) -> Result<()> {
     info!("Manipulating log file: {}", log_path);
+    if skip_completed(net_mon, resume, log_path).await? {
+        return Ok(());
+    }
     let content = fs::read_to_string(log_path).await.context("Failed to read log file")?;
     let filtered_content: String = content
         .lines()
@@ -267,8 +704,11 @@ async fn manipulate_log_file(net_mon: &NetMon, log_path: &str) -> Result<()> {
     Ok(())
 }
 
-async fn remove_network_traces(net_mon: &NetMon) -> Result<()> {
+async fn remove_network_traces(net_mon: &NetMon, resume: &ResumeState) -> Result<()> {
     info!("Removing network traces...");
+    if skip_completed(net_mon, resume, "network_traces").await? {
+        return Ok(());
+    }
 
     // Flush iptables
     let iptables_output = Command::new("iptables")
@@ -303,18 +743,3 @@ async fn remove_network_traces(net_mon: &NetMon) -> Result<()> {
     net_mon.get_sender().send(Action::NetworkTraceRemoved("iptables and DNS cache flushed".to_string())).await?;
     Ok(())
 }
-
-fn setup_logging() -> Result<()> {
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {l} - {m}\n")))
-        .build("./safe/tmp/cleanup.log")
-        .context("Failed to create log file")?;
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(Root::builder().appender("logfile").build(LevelFilter::Info))
-        .context("Failed to configure logger")?;
-
-    log4rs::init_config(config).context("Failed to initialize logger")?;
-    Ok(())
-}
\ No newline at end of file
@@ -1,13 +1,18 @@
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
-use std::collections::VecDeque;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
 use std::sync::Arc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use chrono::{Local, Utc, DateTime};
 use serde::{Serialize, Deserialize};
 use std::path::Path;
 use std::fs;
 use crate::main::Action;
+use crate::audit::AuditSink;
+use crate::journal::Journal;
 
 // Ensure chrono DateTime types can be serialized/deserialized with serde
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,20 +22,316 @@ pub enum Action {
     NetworkTraceRemoved(String),
     BashHistoryCleared,
     TimestampUpdated(String),
+    /// Container engine object, as `<type>:<id>`.
+    ObjectRemoved(String),
+    /// Path moved to the trash, and where it went.
+    Trashed(String, String),
+    /// Stale lock, pid or socket file removed, and why it was stale.
+    StaleRemoved(String, String),
+    /// Dry run: stale file that would be removed, and why it is stale.
+    StalePlanned(String, String),
+    OverwriteStarted(String),
+    /// Path, pass number and bytes written in that pass.
+    OverwritePass(String, usize, u64),
+    /// Overwrite stopped by cancellation: path, pass number and bytes of
+    /// that pass written (and synced) from the start of the file.
+    OverwriteInterrupted(String, usize, u64),
+    /// Path and bytes reclaimed by removing it.
+    SpaceFreed(String, u64),
+    /// Dry run: target and the bytes removing it would free.
+    Planned(String, u64),
+    /// Item and how long it took, in milliseconds.
+    ItemTimed(String, u64),
+    /// Task name and its wall time, in milliseconds.
+    TaskFinished(String, u64),
+    Skipped(String),
+    /// File left in place because a process holds it open: path, PID and
+    /// command line.
+    FileInUse(String, u32, String),
+    /// Daemon mode: task and the scheduled time it did not run at, because
+    /// the daemon was not running or fell behind.
+    MissedRun(String, DateTime<Utc>),
+    /// Daemon mode: task and the run time it skipped because its previous
+    /// run was still going.
+    OverlappingRun(String, DateTime<Utc>),
+    /// Task, the attempt that failed and the delay before the next one.
+    Retrying(String, u32, u64),
+    /// Task not run because a task it depends on failed: task and
+    /// dependency (`hook <name>` for a failed pre-hook).
+    DependencyFailed(String, String),
+    /// Task not run because the process lacks capabilities it needs:
+    /// task and the missing capabilities.
+    MissingPrivileges(String, String),
+    /// Hook that ran to completion: name, exit code (none if killed by a
+    /// signal), stdout and stderr.
+    HookFinished(String, Option<i32>, String, String),
+    /// Hook that failed to start, timed out or exited non-zero, and why.
+    HookFailed(String, String),
+    /// Task and why it was stopped: a timeout or a signal.
+    Cancelled(String, String),
+    /// Target and why it could not be cleaned.
+    TargetFailed(String, String),
     Error(String),
 }
 
+impl Action {
+    /// The target this action marks as finished, used to resume a session.
+    pub fn completed_target(&self) -> Option<&str> {
+        match self {
+            Action::FileDeleted(path)
+            | Action::LogManipulated(path)
+            | Action::TimestampUpdated(path)
+            | Action::ObjectRemoved(path)
+            | Action::Trashed(path, _)
+            | Action::StaleRemoved(path, _) => Some(path),
+            Action::BashHistoryCleared => Some("bash_history"),
+            Action::NetworkTraceRemoved(_) => Some("network_traces"),
+            _ => None,
+        }
+    }
+
+    /// Variant name, used as the key for per-kind counters.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::FileDeleted(_) => "FileDeleted",
+            Action::LogManipulated(_) => "LogManipulated",
+            Action::NetworkTraceRemoved(_) => "NetworkTraceRemoved",
+            Action::BashHistoryCleared => "BashHistoryCleared",
+            Action::TimestampUpdated(_) => "TimestampUpdated",
+            Action::ObjectRemoved(_) => "ObjectRemoved",
+            Action::Trashed(..) => "Trashed",
+            Action::StaleRemoved(..) => "StaleRemoved",
+            Action::StalePlanned(..) => "StalePlanned",
+            Action::OverwriteStarted(_) => "OverwriteStarted",
+            Action::OverwritePass(..) => "OverwritePass",
+            Action::OverwriteInterrupted(..) => "OverwriteInterrupted",
+            Action::SpaceFreed(..) => "SpaceFreed",
+            Action::Planned(..) => "Planned",
+            Action::ItemTimed(..) => "ItemTimed",
+            Action::TaskFinished(..) => "TaskFinished",
+            Action::Skipped(_) => "Skipped",
+            Action::FileInUse(..) => "FileInUse",
+            Action::MissedRun(..) => "MissedRun",
+            Action::OverlappingRun(..) => "OverlappingRun",
+            Action::Retrying(..) => "Retrying",
+            Action::Cancelled(..) => "Cancelled",
+            Action::DependencyFailed(..) => "DependencyFailed",
+            Action::MissingPrivileges(..) => "MissingPrivileges",
+            Action::HookFinished(..) => "HookFinished",
+            Action::HookFailed(..) => "HookFailed",
+            Action::TargetFailed(..) => "TargetFailed",
+            Action::Error(_) => "Error",
+        }
+    }
+
+    /// Human-readable payload, used by the text report formats.
+    pub fn detail(&self) -> String {
+        match self {
+            Action::FileDeleted(s)
+            | Action::LogManipulated(s)
+            | Action::NetworkTraceRemoved(s)
+            | Action::TimestampUpdated(s)
+            | Action::ObjectRemoved(s)
+            | Action::OverwriteStarted(s)
+            | Action::Skipped(s)
+            | Action::Error(s) => s.clone(),
+            Action::BashHistoryCleared => String::new(),
+            Action::OverwritePass(path, pass, bytes) => format!("{} (pass {}, {} bytes)", path, pass, bytes),
+            Action::OverwriteInterrupted(path, pass, bytes) => format!("{} (pass {} stopped after {} bytes)", path, pass, bytes),
+            Action::Retrying(task, attempt, ms) => format!("{} (attempt {} failed, retrying in {} ms)", task, attempt, ms),
+            Action::Cancelled(task, reason) => format!("{}: {}", task, reason),
+            Action::DependencyFailed(task, dependency) => format!("{} (after failed {})", task, dependency),
+            Action::HookFinished(hook, code, _, _) => match code {
+                Some(code) => format!("{} (exit {})", hook, code),
+                None => format!("{} (killed)", hook),
+            },
+            Action::Trashed(path, trashed) => format!("{} -> {}", path, trashed),
+            Action::StaleRemoved(path, reason) | Action::StalePlanned(path, reason) => format!("{} ({})", path, reason),
+            Action::MissingPrivileges(task, missing) => format!("{} (needs {})", task, missing),
+            Action::HookFailed(hook, reason) => format!("{}: {}", hook, reason),
+            Action::SpaceFreed(path, bytes) | Action::Planned(path, bytes) => format!("{} ({} bytes)", path, bytes),
+            Action::ItemTimed(item, ms) | Action::TaskFinished(item, ms) => format!("{} ({} ms)", item, ms),
+            Action::TargetFailed(target, reason) => format!("{}: {}", target, reason),
+            Action::FileInUse(path, pid, command) => format!("{} (pid {}, {})", path, pid, command),
+            Action::MissedRun(task, at) | Action::OverlappingRun(task, at) => format!("{} at {}", task, at.to_rfc3339()),
+        }
+    }
+
+    /// Coarse class of an `Error` message, from the io::Error text it
+    /// usually wraps.
+    pub fn error_kind(&self) -> Option<&'static str> {
+        let msg = match self {
+            Action::Error(msg) | Action::TargetFailed(_, msg) | Action::Cancelled(_, msg) | Action::HookFailed(_, msg) => msg,
+            Action::MissingPrivileges(..) => return Some("permission_denied"),
+            _ => return None,
+        };
+        let msg = msg.to_lowercase();
+        let kind = if msg.contains("no such file") || msg.contains("not found") {
+            "not_found"
+        } else if msg.contains("permission denied") || msg.contains("operation not permitted") {
+            "permission_denied"
+        } else if msg.contains("timed out") || msg.contains("timeout") {
+            "timeout"
+        } else if msg.contains("directory not empty") || msg.contains("resource busy") {
+            "busy"
+        } else if msg == "interrupted" {
+            "cancelled"
+        } else {
+            "other"
+        };
+        Some(kind)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct ActionRecord {
+pub struct ActionRecord {
     // Fix timestamp type here
-    timestamp: DateTime<Utc>,
-    action: Action,
+    pub timestamp: DateTime<Utc>,
+    /// The task that sent the action, if it ran inside `in_task`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    /// The user whose files the action concerns, if it ran inside
+    /// `for_user`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub action: Action,
+}
+
+/// An action plus the names of the task and user it was sent for.
+type TaggedAction = (Option<String>, Option<String>, Action);
+
+tokio::task_local! {
+    static CURRENT_TASK: String;
+    static CURRENT_USER: String;
+}
+
+/// Runs `future` as the named task: every action it sends through an
+/// `ActionSender` is recorded with that task name.
+pub async fn in_task<F: Future>(name: &str, future: F) -> F::Output {
+    CURRENT_TASK.scope(name.to_string(), future).await
+}
+
+/// Runs `future` on behalf of the named user: every action it sends is
+/// recorded with that user, for the per-user reports.
+pub async fn for_user<F: Future>(name: &str, future: F) -> F::Output {
+    CURRENT_USER.scope(name.to_string(), future).await
+}
+
+/// Sending half of NetMon's channel. Tags each action with the current
+/// task and user, if any.
+#[derive(Debug, Clone)]
+pub struct ActionSender {
+    inner: mpsc::Sender<TaggedAction>,
+}
+
+impl ActionSender {
+    pub async fn send(&self, action: Action) -> Result<(), mpsc::error::SendError<Action>> {
+        let task = CURRENT_TASK.try_with(|task| task.clone()).ok();
+        let user = CURRENT_USER.try_with(|user| user.clone()).ok();
+        self.inner
+            .send((task, user, action))
+            .await
+            .map_err(|mpsc::error::SendError((_, _, action))| mpsc::error::SendError(action))
+    }
+}
+
+/// Default number of records NetMon keeps in memory.
+pub const DEFAULT_LOG_CAPACITY: usize = 100_000;
+
+/// How many of the slowest items `ActionStats` keeps.
+pub const SLOWEST_ITEMS: usize = 10;
+
+/// Running totals over every record NetMon has seen, including the ones
+/// evicted from the in-memory log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActionStats {
+    pub total: u64,
+    pub spilled: u64,
+    /// Records the audit collector did not get, its queue being full.
+    #[serde(default)]
+    pub audit_dropped: u64,
+    pub by_kind: BTreeMap<String, u64>,
+    pub files_removed: u64,
+    pub bytes_freed: u64,
+    pub bytes_overwritten: u64,
+    /// Dry run: bytes the planned removals would free.
+    pub bytes_planned: u64,
+    pub errors_by_kind: BTreeMap<String, u64>,
+    pub task_durations_ms: BTreeMap<String, u64>,
+    /// Slowest items first, at most `SLOWEST_ITEMS` long.
+    pub slowest_items: Vec<(String, u64)>,
+    /// Totals of the actions tagged with a user, by user.
+    #[serde(default)]
+    pub by_user: BTreeMap<String, UserStats>,
+}
+
+/// One user's share of a run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserStats {
+    pub actions: u64,
+    pub files_removed: u64,
+    pub bytes_freed: u64,
+    pub bytes_planned: u64,
+    pub errors: u64,
+}
+
+impl UserStats {
+    fn record(&mut self, action: &Action) {
+        self.actions += 1;
+        match action {
+            Action::FileDeleted(_) => self.files_removed += 1,
+            Action::SpaceFreed(_, bytes) => self.bytes_freed += bytes,
+            Action::Planned(_, bytes) => self.bytes_planned += bytes,
+            _ => {}
+        }
+        if action.error_kind().is_some() {
+            self.errors += 1;
+        }
+    }
+}
+
+impl ActionStats {
+    fn record(&mut self, user: Option<&str>, action: &Action) {
+        self.total += 1;
+        if let Some(user) = user {
+            self.by_user.entry(user.to_string()).or_default().record(action);
+        }
+        *self.by_kind.entry(action.kind().to_string()).or_insert(0) += 1;
+
+        match action {
+            Action::FileDeleted(_) => self.files_removed += 1,
+            Action::SpaceFreed(_, bytes) => self.bytes_freed += bytes,
+            Action::Planned(_, bytes) => self.bytes_planned += bytes,
+            Action::OverwritePass(_, _, bytes) => self.bytes_overwritten += bytes,
+            Action::TaskFinished(task, ms) => {
+                *self.task_durations_ms.entry(task.clone()).or_insert(0) += ms;
+            }
+            Action::ItemTimed(item, ms) => {
+                let at = self.slowest_items.partition_point(|(_, slower)| slower >= ms);
+                if at < SLOWEST_ITEMS {
+                    self.slowest_items.insert(at, (item.clone(), *ms));
+                    self.slowest_items.truncate(SLOWEST_ITEMS);
+                }
+            }
+            _ => {}
+        }
+        if let Some(kind) = action.error_kind() {
+            *self.errors_by_kind.entry(kind.to_string()).or_insert(0) += 1;
+        }
+    }
 }
 
 pub struct NetMon {
-    sender: mpsc::Sender<Action>,
-    receiver: Arc<RwLock<mpsc::Receiver<Action>>>,
+    sender: mpsc::Sender<TaggedAction>,
+    receiver: Arc<RwLock<mpsc::Receiver<TaggedAction>>>,
     log: Arc<RwLock<VecDeque<ActionRecord>>>,
+    stats: Arc<RwLock<ActionStats>>,
+    capacity: usize,
+    journal: Option<Arc<Journal>>,
+    audit: Option<Arc<AuditSink>>,
+    /// Tells the monitor to stop taking actions; see `close`.
+    closing: Arc<Notify>,
+    monitor: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl NetMon {
@@ -40,35 +341,136 @@ impl NetMon {
             sender,
             receiver: Arc::new(RwLock::new(receiver)),
             log: Arc::new(RwLock::new(VecDeque::new())),
+            stats: Arc::new(RwLock::new(ActionStats::default())),
+            capacity: DEFAULT_LOG_CAPACITY,
+            journal: None,
+            audit: None,
+            closing: Arc::new(Notify::new()),
+            monitor: Mutex::new(None),
         }
     }
 
-    pub fn get_sender(&self) -> mpsc::Sender<Action> {
-        self.sender.clone()
+    /// Like `new`, but every recorded action is also appended to `journal`.
+    pub fn with_journal(journal: Journal) -> Self {
+        NetMon { journal: Some(Arc::new(journal)), ..Self::new() }
+    }
+
+    /// Caps the in-memory log at `capacity` records (0 means unbounded).
+    /// Older records are evicted; with a journal attached `get_actions`
+    /// reads them back from it, and they are always reflected in
+    /// `get_stats`.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Also forwards every record to syslog or journald.
+    pub fn with_audit(mut self, audit: AuditSink) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    pub fn get_sender(&self) -> ActionSender {
+        ActionSender { inner: self.sender.clone() }
     }
 
     pub async fn start_monitoring(&self) {
         let receiver = self.receiver.clone();
         let log = self.log.clone();
+        let stats = self.stats.clone();
+        let capacity = self.capacity;
+        let journal = self.journal.clone();
+        let audit = self.audit.clone();
+        let closing = self.closing.clone();
+        if capacity > 0 && journal.is_none() {
+            log::warn!("NetMon log capped at {} records without a journal; older records will be dropped", capacity);
+        }
 
-        tokio::spawn(async move {
+        let monitor = tokio::spawn(async move {
+            let mut closed = false;
             loop {
                 let mut receiver = receiver.write();
-                if let Some(action) = receiver.recv().await {
+                let received = tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = closing.notified(), if !closed => {
+                        // Refuse new actions; the queued ones still come
+                        // through before `recv` returns `None`.
+                        receiver.close();
+                        closed = true;
+                        continue;
+                    }
+                };
+                if let Some((task, user, action)) = received {
                     // Use Utc for proper timestamp
                     let timestamp = Utc::now();
-                    let record = ActionRecord { timestamp, action: action.clone() };
-                    log.write().push_back(record);
-                    println!("[{}] Action recorded: {:?}", timestamp, action);
+                    let record = ActionRecord { timestamp, task, user, action: action.clone() };
+                    if let Some(journal) = &journal {
+                        if let Err(e) = journal.append(&record) {
+                            log::error!("Journal write failed: {:#}", e);
+                        }
+                    }
+                    if let Some(audit) = &audit {
+                        if let Err(e) = audit.send(&record) {
+                            let mut stats = stats.write();
+                            if stats.audit_dropped == 0 {
+                                log::error!("Audit forward failed, counting dropped records: {:#}", e);
+                            }
+                            stats.audit_dropped += 1;
+                        }
+                    }
+                    stats.write().record(record.user.as_deref(), &action);
+                    let mut log = log.write();
+                    log.push_back(record);
+                    if capacity > 0 {
+                        while log.len() > capacity {
+                            log.pop_front();
+                            stats.write().spilled += 1;
+                        }
+                    }
+                    drop(log);
+                    log::debug!("[{}] Action recorded: {:?}", timestamp, action);
+                } else {
+                    // Closed and drained, or every sender is gone.
+                    break;
                 }
             }
         });
+        *self.monitor.lock() = Some(monitor);
     }
 
+    /// Stops taking actions and waits until every action already queued is
+    /// recorded, so `get_actions` and `get_stats` see all of them. Senders
+    /// still held elsewhere (the daemon keeps some) fail from then on.
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let monitor = self.monitor.lock().take();
+        if let Some(monitor) = monitor {
+            if let Err(e) = monitor.await {
+                log::error!("NetMon monitor failed: {}", e);
+            }
+        }
+    }
+
+    /// Every action recorded. Once the in-memory log has evicted some,
+    /// they are read back from the journal; without one, only the newest
+    /// `capacity` are left.
     pub async fn get_actions(&self) -> Vec<ActionRecord> {
+        let spilled = self.stats.read().spilled;
+        if let Some(journal) = self.journal.clone().filter(|_| spilled > 0) {
+            match tokio::task::spawn_blocking(move || journal.records()).await {
+                Ok(Ok(records)) => return records,
+                Ok(Err(e)) => log::error!("Cannot read evicted records back from the journal: {:#}", e),
+                Err(e) => log::error!("Journal read panicked: {}", e),
+            }
+            log::warn!("Only the last {} of {} records are available", self.capacity, self.stats.read().total);
+        }
         self.log.read().iter().cloned().collect()
     }
 
+    pub async fn get_stats(&self) -> ActionStats {
+        self.stats.read().clone()
+    }
+
     pub async fn clear_actions(&self) {
         self.log.write().clear();
     }
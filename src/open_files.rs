@@ -0,0 +1,84 @@
+//! Which files live processes hold open, from `/proc/<pid>/fd`. Removing
+//! such a file frees no space until the holder closes it and can break
+//! the holder, so temp cleanup leaves them alone.
+//!
+//! Only processes whose fd dir we may read are seen: all of them as root,
+//! otherwise only our own user's.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+const PROC: &str = "/proc";
+
+/// What to do with a target that has files open in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnOpen {
+    /// Remove everything except the open files (and the dirs holding them).
+    #[default]
+    SkipFiles,
+    /// Leave the whole target as it is.
+    SkipTarget,
+}
+
+#[derive(Debug, Clone)]
+pub struct Holder {
+    pub pid: u32,
+    pub command: String,
+}
+
+/// Open files by path, with the processes holding them. Blocking.
+pub fn open_files() -> HashMap<PathBuf, Vec<Holder>> {
+    let mut open: HashMap<PathBuf, Vec<Holder>> = HashMap::new();
+    let Ok(procs) = fs::read_dir(PROC) else { return open };
+    for entry in procs.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue };
+        // Processes come and go while we scan; skip what we cannot read.
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else { continue };
+        let mut command = None;
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else { continue };
+            // Sockets, pipes and anon inodes read as `socket:[123]` etc.
+            if !target.is_absolute() {
+                continue;
+            }
+            // Already unlinked; nothing left to skip.
+            if target.as_os_str().to_string_lossy().ends_with(" (deleted)") {
+                continue;
+            }
+            let command = command.get_or_insert_with(|| process_command(pid)).clone();
+            let holders = open.entry(target).or_default();
+            if !holders.iter().any(|h| h.pid == pid) {
+                holders.push(Holder { pid, command });
+            }
+        }
+    }
+    open
+}
+
+fn process_command(pid: u32) -> String {
+    let proc_dir = Path::new(PROC).join(pid.to_string());
+    let cmdline = fs::read(proc_dir.join("cmdline")).unwrap_or_default();
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    if !args.is_empty() {
+        return args.join(" ");
+    }
+    // Kernel threads and zombies have no cmdline.
+    fs::read_to_string(proc_dir.join("comm")).map(|comm| comm.trim().to_string()).unwrap_or_default()
+}
+
+/// Open files at or below `path`, which should be canonical.
+pub fn open_under<'a>(open: &'a HashMap<PathBuf, Vec<Holder>>, path: &Path) -> Vec<(&'a Path, &'a [Holder])> {
+    let mut held: Vec<(&Path, &[Holder])> = open
+        .iter()
+        .filter(|(file, _)| file.starts_with(path))
+        .map(|(file, holders)| (file.as_path(), holders.as_slice()))
+        .collect();
+    held.sort_by_key(|(file, _)| *file);
+    held
+}
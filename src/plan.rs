@@ -0,0 +1,372 @@
+//! The set of tasks a run executes, loaded from the JSON file given with
+//! `run --plan` or built in. Its hash identifies a run's intent in the
+//! session header, so two reports can be checked for running the same plan.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, Context, bail};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::caches::{CacheKind, CacheTask};
+use crate::core_dumps::CoreDumpsTask;
+use crate::docker::DockerTask;
+use crate::homes::UserHomesTask;
+use crate::hooks::Hook;
+use crate::open_files::OnOpen;
+use crate::privileges::Capability;
+use crate::prune::{DanglingSymlinksTask, EmptyDirsTask};
+use crate::rotated_logs::RotatedLogsTask;
+use crate::schedule::Schedule;
+use crate::stale_files::StaleFilesTask;
+use crate::supervise::RetryPolicy;
+use crate::trash::{TrashOp, TrashTask};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanTask {
+    /// Unique within the plan; used in reports and to tag actions.
+    pub name: String,
+    /// Order in daemon mode, lowest first among tasks whose dependencies
+    /// have run; ties keep plan order.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+    /// When daemon mode runs the task on its own, besides disk pressure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+    /// Cancel the task if it runs longer than this, retries included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Retry the task when it fails with a transient IO error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Tasks that must finish successfully before this one starts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// Tasks that may only start once this one finished successfully.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<String>,
+    /// Hooks run, in order, before the task; if one fails the task is
+    /// not run.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre: Vec<String>,
+    /// Hooks run, in order, after the task, whether it succeeded or not.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post: Vec<String>,
+    /// Capabilities the task needs besides those its kind implies, e.g.
+    /// `dac_override` for removing other users' files.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<Capability>,
+    #[serde(flatten)]
+    pub kind: TaskKind,
+}
+
+impl PlanTask {
+    pub fn new(name: &str, kind: TaskKind) -> Self {
+        PlanTask {
+            name: name.to_string(),
+            priority: 0,
+            schedule: None,
+            timeout_secs: None,
+            retry: None,
+            after: Vec::new(),
+            before: Vec::new(),
+            pre: Vec::new(),
+            post: Vec::new(),
+            requires: Vec::new(),
+            kind,
+        }
+    }
+
+    /// Everything the task needs to run: its kind's capabilities and
+    /// `requires`.
+    pub fn required_capabilities(&self) -> Vec<Capability> {
+        let needs: BTreeSet<Capability> = self.kind.capabilities().iter().chain(&self.requires).copied().collect();
+        needs.into_iter().collect()
+    }
+}
+
+fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+/// What a task does, tagged by `"task"` in the plan file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum TaskKind {
+    RemoveTempFiles {
+        path: String,
+        /// What to do when files under `path` are held open.
+        #[serde(default)]
+        on_open: OnOpen,
+    },
+    ClearBashHistory,
+    UpdateTimestamps { files: Vec<String> },
+    SecureDeleteFile { path: String },
+    ManipulateLogFile { path: String },
+    RemoveNetworkTraces,
+    Cache(CacheTask),
+    Docker(DockerTask),
+    RotatedLogs(RotatedLogsTask),
+    UserHomes(UserHomesTask),
+    Trash(TrashTask),
+    StaleFiles(StaleFilesTask),
+    CoreDumps(CoreDumpsTask),
+    EmptyDirs(EmptyDirsTask),
+    DanglingSymlinks(DanglingSymlinksTask),
+}
+
+impl TaskKind {
+    /// How the task cleans its targets, as shown for review.
+    pub fn method(&self) -> &'static str {
+        match self {
+            TaskKind::RemoveTempFiles { on_open: OnOpen::SkipFiles, .. } => "remove tree, leaving open files",
+            TaskKind::RemoveTempFiles { on_open: OnOpen::SkipTarget, .. } => "remove tree unless files in it are open",
+            TaskKind::ClearBashHistory => "clear shell history",
+            TaskKind::UpdateTimestamps { .. } => "set modification times to now",
+            TaskKind::SecureDeleteFile { .. } => "overwrite in place, then delete",
+            TaskKind::ManipulateLogFile { .. } => "rewrite log without matching lines",
+            TaskKind::RemoveNetworkTraces => "flush iptables rules and DNS cache",
+            TaskKind::Cache(_) => "remove cache entries",
+            TaskKind::Docker(_) => "remove through the container engine API",
+            TaskKind::RotatedLogs(_) => "remove rotated logs",
+            TaskKind::UserHomes(_) => "remove cache and trash entries as each user",
+            TaskKind::Trash(TrashTask { op: TrashOp::Move, .. }) => "move to the trash",
+            TaskKind::Trash(TrashTask { op: TrashOp::Empty, .. }) => "remove from the trash",
+            TaskKind::StaleFiles(_) => "remove lock, pid and socket files of dead processes",
+            TaskKind::CoreDumps(CoreDumpsTask { sanitize: true, .. }) => "overwrite old core dumps, then delete",
+            TaskKind::CoreDumps(_) => "remove old core dumps",
+            TaskKind::EmptyDirs(_) => "remove empty directories, deepest first",
+            TaskKind::DanglingSymlinks(_) => "remove symlinks to missing targets",
+        }
+    }
+
+    /// Capabilities the task always needs, whatever its targets.
+    pub fn capabilities(&self) -> &'static [Capability] {
+        match self {
+            TaskKind::RemoveNetworkTraces => &[Capability::NetAdmin],
+            TaskKind::Cache(CacheTask { cache: CacheKind::Apt | CacheKind::Dnf, .. }) => &[Capability::DacOverride],
+            TaskKind::UserHomes(_) => &[Capability::Setuid, Capability::Setgid],
+            TaskKind::CoreDumps(_) => &[Capability::DacOverride],
+            _ => &[],
+        }
+    }
+
+    /// Whether the task finds its targets at run time (caches, docker,
+    /// rotated logs, user homes, trash, ...) instead of naming them in the
+    /// plan. A dry run sizes these up by running them.
+    pub fn discovers_targets(&self) -> bool {
+        !matches!(
+            self,
+            TaskKind::RemoveTempFiles { .. }
+                | TaskKind::SecureDeleteFile { .. }
+                | TaskKind::ManipulateLogFile { .. }
+                | TaskKind::UpdateTimestamps { .. }
+                | TaskKind::ClearBashHistory
+                | TaskKind::RemoveNetworkTraces
+        )
+    }
+
+    /// Fixed targets of the task, as recorded in its actions; none for a
+    /// task that `discovers_targets`.
+    pub fn targets(&self) -> Vec<String> {
+        match self {
+            TaskKind::RemoveTempFiles { path, .. }
+            | TaskKind::SecureDeleteFile { path }
+            | TaskKind::ManipulateLogFile { path } => vec![path.clone()],
+            TaskKind::UpdateTimestamps { files } => files.clone(),
+            TaskKind::ClearBashHistory => vec!["bash_history".to_string()],
+            TaskKind::RemoveNetworkTraces => vec!["network_traces".to_string()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// Commands tasks can run before and after themselves, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hooks: BTreeMap<String, Hook>,
+    pub tasks: Vec<PlanTask>,
+}
+
+impl Plan {
+    /// The tasks `main()` runs when no plan file is given.
+    pub fn builtin() -> Self {
+        Plan {
+            hooks: BTreeMap::new(),
+            tasks: vec![
+                PlanTask::new("remove_temp_files", TaskKind::RemoveTempFiles { path: "/tmp/cleancleanclean".to_string(), on_open: OnOpen::default() }),
+                PlanTask::new("clear_bash_history", TaskKind::ClearBashHistory),
+                PlanTask::new("update_timestamps", TaskKind::UpdateTimestamps {
+                    files: vec!["/tmp/example1.txt".to_string(), "/tmp/example2.txt".to_string()],
+                }),
+                PlanTask::new("secure_delete_file", TaskKind::SecureDeleteFile { path: "./safe/tmp/sensitive_data.txt".to_string() }),
+                PlanTask::new("manipulate_log_file", TaskKind::ManipulateLogFile { path: "./safe/var/log/system.log".to_string() }),
+                PlanTask::new("remove_network_traces", TaskKind::RemoveNetworkTraces),
+            ],
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read plan: {}", path.display()))?;
+        let plan: Plan = serde_json::from_str(&json)
+            .with_context(|| format!("Invalid plan: {}", path.display()))?;
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, hook) in &self.hooks {
+            if hook.command.is_empty() {
+                bail!("Hook {} has no command", name);
+            }
+        }
+        let mut names = HashSet::new();
+        for task in &self.tasks {
+            if !names.insert(task.name.as_str()) {
+                bail!("Duplicate task name in plan: {}", task.name);
+            }
+            if let Some(schedule) = &task.schedule {
+                schedule.validate().with_context(|| format!("Task {}", task.name))?;
+            }
+            if let Some(hook) = task.pre.iter().chain(&task.post).find(|hook| !self.hooks.contains_key(*hook)) {
+                bail!("Task {} refers to unknown hook {}", task.name, hook);
+            }
+        }
+        self.ordered()?;
+        Ok(())
+    }
+
+    /// For each task (by index), the indices of the tasks it waits for,
+    /// from its own `after` and the other tasks' `before`.
+    pub fn dependencies(&self) -> Result<Vec<Vec<usize>>> {
+        let index: HashMap<&str, usize> = self.tasks.iter().enumerate().map(|(i, t)| (t.name.as_str(), i)).collect();
+        let lookup = |task: &PlanTask, name: &str| {
+            match index.get(name) {
+                Some(&i) if i != index[task.name.as_str()] => Ok(i),
+                Some(_) => bail!("Task {} depends on itself", task.name),
+                None => bail!("Task {} refers to unknown task {}", task.name, name),
+            }
+        };
+
+        let mut deps: Vec<Vec<usize>> = vec![Vec::new(); self.tasks.len()];
+        for (i, task) in self.tasks.iter().enumerate() {
+            for name in &task.after {
+                deps[i].push(lookup(task, name)?);
+            }
+            for name in &task.before {
+                deps[lookup(task, name)?].push(i);
+            }
+        }
+        for dep in &mut deps {
+            dep.sort_unstable();
+            dep.dedup();
+        }
+        Ok(deps)
+    }
+
+    /// The tasks in an order that respects their dependencies, lowest
+    /// priority first among the ready ones, for running one at a time.
+    /// Fails on a dependency cycle.
+    pub fn ordered(&self) -> Result<Vec<&PlanTask>> {
+        let deps = self.dependencies()?;
+        let mut waiting: Vec<usize> = deps.iter().map(Vec::len).collect();
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); self.tasks.len()];
+        for (i, dep) in deps.iter().enumerate() {
+            for &d in dep {
+                dependents[d].push(i);
+            }
+        }
+
+        let key = |i: usize| Reverse((self.tasks[i].priority, i));
+        let mut ready: BinaryHeap<_> = (0..self.tasks.len()).filter(|&i| waiting[i] == 0).map(key).collect();
+        let mut order = Vec::with_capacity(self.tasks.len());
+        while let Some(Reverse((_, i))) = ready.pop() {
+            order.push(&self.tasks[i]);
+            for &d in &dependents[i] {
+                waiting[d] -= 1;
+                if waiting[d] == 0 {
+                    ready.push(key(d));
+                }
+            }
+        }
+
+        if order.len() < self.tasks.len() {
+            let stuck: Vec<&str> = (0..self.tasks.len())
+                .filter(|&i| waiting[i] > 0)
+                .map(|i| self.tasks[i].name.as_str())
+                .collect();
+            bail!("Dependency cycle among tasks: {}", stuck.join(", "));
+        }
+        Ok(order)
+    }
+
+    /// Hex SHA-256 of the plan's canonical JSON.
+    pub fn hash(&self) -> String {
+        let json = serde_json::to_vec(self).expect("plan serializes");
+        Sha256::digest(&json).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(json: &str) -> Plan {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn names(plan: &Plan) -> Vec<&str> {
+        plan.ordered().unwrap().iter().map(|task| task.name.as_str()).collect()
+    }
+
+    #[test]
+    fn ordered_follows_after_and_before() {
+        let plan = plan(r#"{"tasks": [
+            {"name": "c", "task": "clear_bash_history", "after": ["b"]},
+            {"name": "b", "task": "remove_network_traces"},
+            {"name": "a", "task": "remove_temp_files", "path": "/tmp", "before": ["b"]}
+        ]}"#);
+        assert_eq!(names(&plan), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ordered_breaks_priority_ties_by_plan_order() {
+        let plan = plan(r#"{"tasks": [
+            {"name": "late", "task": "clear_bash_history", "priority": 5},
+            {"name": "first", "task": "remove_network_traces", "priority": -1},
+            {"name": "tie-1", "task": "remove_temp_files", "path": "/tmp"},
+            {"name": "tie-2", "task": "remove_temp_files", "path": "/var/tmp"},
+            {"name": "blocked", "task": "remove_temp_files", "path": "/x", "priority": -9, "after": ["late"]}
+        ]}"#);
+        assert_eq!(names(&plan), ["first", "tie-1", "tie-2", "late", "blocked"]);
+    }
+
+    #[test]
+    fn validate_rejects_cycles_and_bad_references() {
+        let cycle = plan(r#"{"tasks": [
+            {"name": "a", "task": "clear_bash_history", "after": ["c"]},
+            {"name": "b", "task": "clear_bash_history", "after": ["a"]},
+            {"name": "c", "task": "clear_bash_history", "after": ["b"]},
+            {"name": "free", "task": "clear_bash_history"}
+        ]}"#);
+        let error = cycle.validate().unwrap_err().to_string();
+        assert_eq!(error, "Dependency cycle among tasks: a, b, c");
+
+        let cases = [
+            (r#"{"tasks": [{"name": "a", "task": "clear_bash_history", "after": ["a"]}]}"#, "Task a depends on itself"),
+            (r#"{"tasks": [{"name": "a", "task": "clear_bash_history", "before": ["nope"]}]}"#, "Task a refers to unknown task nope"),
+            (
+                r#"{"tasks": [{"name": "a", "task": "clear_bash_history"}, {"name": "a", "task": "remove_network_traces"}]}"#,
+                "Duplicate task name in plan: a",
+            ),
+            (r#"{"tasks": [{"name": "a", "task": "clear_bash_history", "pre": ["nope"]}]}"#, "Task a refers to unknown hook nope"),
+        ];
+        for (json, expected) in cases {
+            assert_eq!(plan(json).validate().unwrap_err().to_string(), expected);
+        }
+        assert!(Plan::builtin().validate().is_ok());
+    }
+}
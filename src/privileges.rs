@@ -0,0 +1,309 @@
+//! What the process may do, checked before tasks run instead of each task
+//! failing on its own permission errors. A task's needs are the Linux
+//! capabilities its kind implies plus the ones listed under `requires` in
+//! the plan; a task whose needs are not met is skipped with
+//! `MissingPrivileges`.
+//!
+//! `--drop-privileges USER` runs the tasks that need elevated rights
+//! first, then switches the effective uid and gid to `USER` for the rest
+//! of the plan. The switch applies to the whole process and is undone
+//! before reports are written. Once it is made, `run_as` can no longer
+//! switch a thread to another user, so plans that would leave a task
+//! acting as users (`user_homes`) for after the switch are rejected.
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use anyhow::{Result, Context, bail};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::net_mon::{Action, NetMon};
+use crate::plan::{Plan, PlanTask, TaskKind};
+
+/// The capabilities tasks may need, by their `CAP_` name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Change file owners.
+    Chown,
+    /// Read, write and remove files regardless of their permissions.
+    DacOverride,
+    /// Read files and list directories regardless of their permissions.
+    DacReadSearch,
+    /// Set timestamps and modes of files owned by others.
+    Fowner,
+    /// Signal other users' processes.
+    Kill,
+    /// Switch group ids, e.g. to act as another user.
+    Setgid,
+    /// Switch user ids.
+    Setuid,
+    /// Change firewall rules and network settings.
+    NetAdmin,
+    SysAdmin,
+}
+
+impl Capability {
+    /// Bit number, from `linux/capability.h`.
+    fn bit(self) -> u32 {
+        match self {
+            Capability::Chown => 0,
+            Capability::DacOverride => 1,
+            Capability::DacReadSearch => 2,
+            Capability::Fowner => 3,
+            Capability::Kill => 5,
+            Capability::Setgid => 6,
+            Capability::Setuid => 7,
+            Capability::NetAdmin => 12,
+            Capability::SysAdmin => 21,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Chown => "chown",
+            Capability::DacOverride => "dac_override",
+            Capability::DacReadSearch => "dac_read_search",
+            Capability::Fowner => "fowner",
+            Capability::Kill => "kill",
+            Capability::Setgid => "setgid",
+            Capability::Setuid => "setuid",
+            Capability::NetAdmin => "net_admin",
+            Capability::SysAdmin => "sys_admin",
+        })
+    }
+}
+
+/// Effective uid and capabilities of the process.
+#[derive(Debug, Clone, Copy)]
+pub struct Privileges {
+    pub euid: u32,
+    /// `CapEff` from `/proc/self/status`.
+    pub effective: u64,
+}
+
+impl Privileges {
+    pub fn current() -> Self {
+        let euid = unsafe { libc::geteuid() };
+        let effective = fs::read_to_string("/proc/self/status")
+            .ok()
+            .and_then(|status| {
+                let line = status.lines().find(|line| line.starts_with("CapEff:"))?;
+                u64::from_str_radix(line.split_whitespace().nth(1)?, 16).ok()
+            })
+            // Without /proc, assume root has every capability.
+            .unwrap_or(if euid == 0 { u64::MAX } else { 0 });
+        Self { euid, effective }
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.effective & (1 << capability.bit()) != 0
+    }
+
+    /// The capabilities `task` needs that the process lacks.
+    pub fn missing(&self, task: &PlanTask) -> Vec<Capability> {
+        task.required_capabilities().into_iter().filter(|cap| !self.has(*cap)).collect()
+    }
+}
+
+impl fmt::Display for Privileges {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uid {}, capabilities {:#x}", self.euid, self.effective)
+    }
+}
+
+/// Logs the process's privileges and the tasks that will be skipped for
+/// lack of them.
+pub fn check_plan<'a>(tasks: impl IntoIterator<Item = &'a PlanTask>) {
+    let privileges = Privileges::current();
+    info!("Running with {}", privileges);
+    for task in tasks {
+        let missing = privileges.missing(task);
+        if !missing.is_empty() {
+            warn!("Task {} needs {}, which this process lacks; it will be skipped", task.name, join(&missing));
+        }
+    }
+}
+
+/// Fails `task` with `MissingPrivileges` if the process lacks what it
+/// needs.
+pub async fn require_privileges(net_mon: &NetMon, task: &PlanTask) -> Result<()> {
+    let missing = Privileges::current().missing(task);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    warn!("Skipping {}: needs {}", task.name, join(&missing));
+    net_mon.get_sender().send(Action::MissingPrivileges(task.name.clone(), join(&missing))).await?;
+    bail!("Task {} needs {}", task.name, join(&missing))
+}
+
+/// For `--drop-privileges`: which tasks are held until the switch to the
+/// unprivileged user. Tasks that need capabilities run before it, and so
+/// do the tasks they depend on. Fails if a task that acts as other users
+/// would be held, as it could only fail after the switch.
+pub fn held_until_drop(plan: &Plan) -> Result<Vec<bool>> {
+    let deps = plan.dependencies()?;
+    let mut privileged = vec![false; plan.tasks.len()];
+    let mut stack: Vec<usize> = (0..plan.tasks.len()).filter(|&i| !plan.tasks[i].required_capabilities().is_empty()).collect();
+    while let Some(i) = stack.pop() {
+        if !std::mem::replace(&mut privileged[i], true) {
+            stack.extend(deps[i].iter().copied());
+        }
+    }
+    for (task, privileged) in plan.tasks.iter().zip(&privileged) {
+        if !privileged && matches!(task.kind, TaskKind::UserHomes(_)) {
+            bail!("Task {} acts as other users, which it cannot do after --drop-privileges", task.name);
+        }
+    }
+    Ok(privileged.iter().map(|privileged| !privileged).collect())
+}
+
+pub fn join(capabilities: &[Capability]) -> String {
+    capabilities.iter().map(Capability::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// A user from the password database.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+pub fn lookup_user(name: &str) -> Result<User> {
+    let c_name = CString::new(name).with_context(|| format!("Invalid user name: {}", name))?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc)).with_context(|| format!("Failed to look up user {}", name));
+    }
+    if result.is_null() {
+        bail!("No such user: {}", name);
+    }
+    let name = unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned();
+    let home = PathBuf::from(unsafe { CStr::from_ptr(pwd.pw_dir) }.to_string_lossy().into_owned());
+    Ok(User { name, uid: pwd.pw_uid, gid: pwd.pw_gid, home })
+}
+
+/// The effective identity switched to another user, switched back when
+/// dropped.
+pub struct DroppedPrivileges {
+    euid: libc::uid_t,
+    egid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+}
+
+impl DroppedPrivileges {
+    /// Switches the effective uid, gid and groups of the whole process to
+    /// `user`'s. The real and saved uids stay, so this can be undone;
+    /// it limits what a mistake can break, it is no sandbox. Until then,
+    /// `run_as` fails: the process can no longer set groups or other uids.
+    pub fn switch_to(user: &User) -> Result<Self> {
+        let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if euid != 0 {
+            bail!("Dropping privileges to {} needs root, running as uid {}", user.name, euid);
+        }
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut groups = vec![0; count.max(0) as usize];
+        if unsafe { libc::getgroups(count, groups.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error()).context("getgroups failed");
+        }
+
+        let saved = Self { euid, egid, groups };
+        // Groups first: after seteuid we may no longer change them.
+        check(unsafe { libc::setgroups(1, &user.gid) }, "setgroups")?;
+        check(unsafe { libc::setegid(user.gid) }, "setegid")?;
+        check(unsafe { libc::seteuid(user.uid) }, "seteuid")?;
+        info!("Switched to user {} (uid {}, gid {})", user.name, user.uid, user.gid);
+        Ok(saved)
+    }
+}
+
+impl Drop for DroppedPrivileges {
+    fn drop(&mut self) {
+        let restored = check(unsafe { libc::seteuid(self.euid) }, "seteuid")
+            .and_then(|_| check(unsafe { libc::setegid(self.egid) }, "setegid"))
+            .and_then(|_| check(unsafe { libc::setgroups(self.groups.len(), self.groups.as_ptr()) }, "setgroups"));
+        match restored {
+            Ok(()) => info!("Switched back to uid {}", self.euid),
+            Err(e) => warn!("Failed to restore privileges: {:#}", e),
+        }
+    }
+}
+
+/// Runs `work` on a thread of its own that acts as `user` (uid, gid and
+/// groups), leaving the rest of the process as it is. On Linux the raw
+/// set*id syscalls change only the calling thread; the thread ends with
+/// `work`, so it never switches back.
+pub async fn run_as<F, R>(user: &User, work: F) -> Result<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (uid, gid) = (user.uid, user.gid);
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name(format!("as-{}", user.name))
+        .spawn(move || {
+            let result = switch_thread(uid, gid).map(|()| work());
+            let _ = sender.send(result);
+        })
+        .context("Failed to start thread")?;
+    receiver.await.context("User thread panicked")?.with_context(|| format!("Failed to act as {}", user.name))
+}
+
+fn switch_thread(uid: libc::uid_t, gid: libc::gid_t) -> Result<()> {
+    if unsafe { libc::geteuid() } == uid {
+        return Ok(());
+    }
+    let groups = [gid];
+    // Each as a raw syscall: the libc wrappers apply to every thread.
+    check(unsafe { libc::syscall(libc::SYS_setgroups, 1, groups.as_ptr()) } as libc::c_int, "setgroups")?;
+    check(unsafe { libc::syscall(libc::SYS_setresgid, gid, gid, gid) } as libc::c_int, "setresgid")?;
+    check(unsafe { libc::syscall(libc::SYS_setresuid, uid, uid, uid) } as libc::c_int, "setresuid")?;
+    Ok(())
+}
+
+fn check(rc: libc::c_int, call: &str) -> Result<()> {
+    if rc != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("{} failed", call));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(json: &str) -> Plan {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn privileged_tasks_and_their_dependencies_run_before_the_drop() {
+        let plan = plan(r#"{"tasks": [
+            {"name": "history", "task": "clear_bash_history"},
+            {"name": "traces", "task": "remove_network_traces", "after": ["history"]},
+            {"name": "tmp", "task": "remove_temp_files", "path": "/tmp", "after": ["traces"]},
+            {"name": "homes", "task": "user_homes"}
+        ]}"#);
+        assert_eq!(held_until_drop(&plan).unwrap(), [false, false, true, false]);
+    }
+
+    #[test]
+    fn user_homes_and_what_it_waits_for_run_before_the_drop() {
+        let plan = plan(r#"{"tasks": [
+            {"name": "tmp", "task": "remove_temp_files", "path": "/tmp"},
+            {"name": "homes", "task": "user_homes", "after": ["tmp"]},
+            {"name": "history", "task": "clear_bash_history", "after": ["homes"]}
+        ]}"#);
+        assert_eq!(held_until_drop(&plan).unwrap(), [false, false, true]);
+    }
+}
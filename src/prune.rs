@@ -0,0 +1,252 @@
+//! Finer-grained removal than `RemoveTempFiles`, which removes whole
+//! trees: pruning the empty directories under a root, bottom-up, and
+//! removing symlinks whose target is gone.
+//!
+//! Both stay on the root's filesystem, never remove the root itself and
+//! leave alone anything excluded by path (with everything below it) or by
+//! entry name, e.g. `.git` or `.keep`.
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::{Result, Context};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Exclusions {
+    /// Paths left alone, with everything below them.
+    #[serde(default)]
+    pub exclude: Vec<PathBuf>,
+    /// Entry names left alone wherever they are.
+    #[serde(default)]
+    pub exclude_names: Vec<String>,
+}
+
+impl Exclusions {
+    fn excludes(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|excluded| path.starts_with(excluded))
+            || path
+                .file_name()
+                .is_some_and(|name| self.exclude_names.iter().any(|excluded| name == excluded.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmptyDirsTask {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub exclusions: Exclusions,
+    /// Only prune dirs not modified for this many days.
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DanglingSymlinksTask {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub exclusions: Exclusions,
+}
+
+impl EmptyDirsTask {
+    /// Dirs that are empty, or hold only dirs that are, children before
+    /// their parents. Blocking.
+    fn candidates(&self) -> Result<Vec<PathBuf>> {
+        let device = fs::metadata(&self.path).with_context(|| format!("Failed to stat {}", self.path.display()))?.dev();
+        let cutoff = self
+            .older_than_days
+            .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days * 86_400)));
+        let mut out = Vec::new();
+        self.collect(&self.path, device, cutoff, &mut out);
+        Ok(out)
+    }
+
+    /// Adds the prunable dirs below `dir` to `out`; returns whether `dir`
+    /// itself is left empty by that.
+    fn collect(&self, dir: &Path, device: u64, cutoff: Option<SystemTime>, out: &mut Vec<PathBuf>) -> bool {
+        let Ok(entries) = fs::read_dir(dir) else { return false };
+        let mut empty = true;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                empty = false;
+                continue;
+            };
+            // Symlinks, files and mount points all keep their parent.
+            if !metadata.is_dir() || metadata.dev() != device || self.exclusions.excludes(&path) {
+                empty = false;
+                continue;
+            }
+            let old_enough = match (cutoff, metadata.modified()) {
+                (Some(cutoff), Ok(modified)) => modified < cutoff,
+                (Some(_), Err(_)) => false,
+                (None, _) => true,
+            };
+            if self.collect(&path, device, cutoff, out) && old_enough {
+                out.push(path);
+            } else {
+                empty = false;
+            }
+        }
+        empty
+    }
+}
+
+impl DanglingSymlinksTask {
+    /// Symlinks whose target does not resolve. Blocking.
+    fn candidates(&self) -> Result<Vec<PathBuf>> {
+        let device = fs::metadata(&self.path).with_context(|| format!("Failed to stat {}", self.path.display()))?.dev();
+        let mut out = Vec::new();
+        self.collect(&self.path, device, &mut out);
+        Ok(out)
+    }
+
+    fn collect(&self, dir: &Path, device: u64, out: &mut Vec<PathBuf>) {
+        let Ok(entries) = fs::read_dir(dir) else { return };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else { continue };
+            if self.exclusions.excludes(&path) {
+                continue;
+            }
+            if metadata.is_symlink() {
+                if is_dangling(&path) {
+                    out.push(path);
+                }
+            } else if metadata.is_dir() && metadata.dev() == device {
+                self.collect(&path, device, out);
+            }
+        }
+    }
+}
+
+/// Whether a symlink's target is missing, or it resolves in a loop.
+fn is_dangling(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(_) => false,
+        Err(e) => e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(libc::ELOOP),
+    }
+}
+
+/// Removes (or, with `dry_run`, lists) the empty dirs under the task's
+/// root, deepest first.
+pub async fn prune_empty_dirs(net_mon: &NetMon, resume: &ResumeState, task: &EmptyDirsTask, dry_run: bool) -> Result<()> {
+    info!("Pruning empty directories under {}...", task.path.display());
+    let scan = task.clone();
+    let candidates = tokio::task::spawn_blocking(move || scan.candidates()).await??;
+    info!("{} empty directories found", candidates.len());
+
+    for path in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, 0)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+        // Not `remove_dir_all`: whatever appeared since the scan stays.
+        match tokio::fs::remove_dir(&path).await {
+            Ok(()) => net_mon.get_sender().send(Action::FileDeleted(target)).await?,
+            Err(e) => {
+                warn!("Failed to remove {}: {}", target, e);
+                net_mon.get_sender().send(Action::TargetFailed(target, format!("Failed to remove: {}", e))).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes (or, with `dry_run`, lists) the dangling symlinks under the
+/// task's root.
+pub async fn remove_dangling_symlinks(net_mon: &NetMon, resume: &ResumeState, task: &DanglingSymlinksTask, dry_run: bool) -> Result<()> {
+    info!("Removing dangling symlinks under {}...", task.path.display());
+    let scan = task.clone();
+    let candidates = tokio::task::spawn_blocking(move || scan.candidates()).await??;
+    info!("{} dangling symlinks found", candidates.len());
+
+    for path in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, 0)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+        // The target may have come back since the scan.
+        if !is_dangling(&path) {
+            info!("{} resolves again, keeping it", target);
+            continue;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => net_mon.get_sender().send(Action::FileDeleted(target)).await?,
+            Err(e) => {
+                warn!("Failed to remove {}: {}", target, e);
+                net_mon.get_sender().send(Action::TargetFailed(target, format!("Failed to remove: {}", e))).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use uuid::Uuid;
+
+    fn relative(paths: Vec<PathBuf>, root: &Path) -> Vec<String> {
+        paths.iter().map(|path| path.strip_prefix(root).unwrap().display().to_string()).collect()
+    }
+
+    fn exclusions(root: &Path) -> Exclusions {
+        Exclusions { exclude: vec![root.join("excluded")], exclude_names: vec![".git".to_string(), ".keep".to_string()] }
+    }
+
+    #[test]
+    fn empty_dirs_children_first_minus_exclusions() {
+        let root = std::env::temp_dir().join(format!("prune-test-{}", Uuid::new_v4()));
+        for dir in ["a/b", "repo/.git", "excluded/x", "files", "mixed/empty"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(root.join("files/f"), b"f").unwrap();
+        symlink("nowhere", root.join("mixed/link")).unwrap();
+
+        let task = EmptyDirsTask { path: root.clone(), exclusions: exclusions(&root), older_than_days: None };
+        let mut pruned = relative(task.candidates().unwrap(), &root);
+        let (b, a) = (pruned.iter().position(|p| p == "a/b"), pruned.iter().position(|p| p == "a"));
+        assert!(b < a, "{:?}", pruned);
+        pruned.sort();
+        assert_eq!(pruned, ["a", "a/b", "mixed/empty"]);
+
+        // Freshly made dirs are too young to prune.
+        let task = EmptyDirsTask { older_than_days: Some(1), ..task };
+        assert!(task.candidates().unwrap().is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dangling_links_minus_exclusions() {
+        let root = std::env::temp_dir().join(format!("prune-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(root.join("excluded")).unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("target"), b"t").unwrap();
+        symlink(root.join("target"), root.join("ok")).unwrap();
+        symlink("missing", root.join("sub/gone")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+        symlink("missing", root.join("excluded/gone")).unwrap();
+        symlink("missing", root.join("sub/.keep")).unwrap();
+
+        let task = DanglingSymlinksTask { path: root.clone(), exclusions: exclusions(&root) };
+        let mut dangling = relative(task.candidates().unwrap(), &root);
+        dangling.sort();
+        assert_eq!(dangling, ["loop", "sub/gone"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! Report renderers. Each format turns the session's action records and
+//! its `RunSummary` into a string that `utils::save_report` writes under
+//! `./safe/tmp/reports/<extension>/`. Every format carries the `Session`
+//! header.
+use std::fmt::Write;
+use std::path::PathBuf;
+use anyhow::{Result, Context};
+use clap::ValueEnum;
+use serde::{Serialize, Deserialize};
+
+use crate::net_mon::ActionRecord;
+use crate::session::Session;
+use crate::summary::RunSummary;
+use crate::utils::{save_report, format_bytes};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum ReportFormat {
+    Json,
+    Jsonl,
+    Csv,
+    Markdown,
+    Html,
+}
+
+impl ReportFormat {
+    pub fn reporter(self) -> Box<dyn Reporter> {
+        match self {
+            ReportFormat::Json => Box::new(JsonReporter),
+            ReportFormat::Jsonl => Box::new(JsonLinesReporter),
+            ReportFormat::Csv => Box::new(CsvReporter),
+            ReportFormat::Markdown => Box::new(MarkdownReporter),
+            ReportFormat::Html => Box::new(HtmlReporter),
+        }
+    }
+}
+
+pub trait Reporter {
+    /// File extension, also used as the report sub-directory.
+    fn extension(&self) -> &'static str;
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String>;
+}
+
+/// Renders the report in `format` and saves it as `<file_name>_<session_id>.<ext>`
+/// (`<session_id>-resumed-<n>` for later runs of a resumed session).
+pub fn write_report(format: ReportFormat, records: &[ActionRecord], summary: &RunSummary, file_name: &str) -> Result<PathBuf> {
+    let reporter = format.reporter();
+    let contents = reporter.render(records, summary)?;
+    save_report(&contents, reporter.extension(), file_name, summary.session.id)
+}
+
+/// Layout of the JSON report: the session header, then the actions.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonReport {
+    pub session: Session,
+    pub actions: Vec<ActionRecord>,
+}
+
+/// The session header and action list as pretty-printed JSON.
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String> {
+        #[derive(Serialize)]
+        struct Borrowed<'a> {
+            session: &'a Session,
+            actions: &'a [ActionRecord],
+        }
+        let report = Borrowed { session: &summary.session, actions: records };
+        serde_json::to_string_pretty(&report).context("Failed to serialize data to JSON")
+    }
+}
+
+/// One JSON object per line, for streaming into log pipelines. The first
+/// line is `{"session": ...}`.
+pub struct JsonLinesReporter;
+
+impl Reporter for JsonLinesReporter {
+    fn extension(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String> {
+        let header = serde_json::json!({ "session": summary.session });
+        let mut out = header.to_string();
+        out.push('\n');
+        for record in records {
+            out.push_str(&serde_json::to_string(record).context("Failed to serialize record")?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// One row per action. The session fields lead every row as `session`
+/// and `session_<field>` columns, so the file stays plain CSV and reports
+/// of several sessions can be concatenated.
+pub struct CsvReporter;
+
+impl Reporter for CsvReporter {
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String> {
+        let session = session_rows(&summary.session);
+        let columns: Vec<String> = session
+            .iter()
+            .map(|(field, _)| match *field {
+                "Session" => "session".to_string(),
+                field => format!("session_{}", field.to_lowercase().replace(' ', "_")),
+            })
+            .collect();
+        let values: Vec<String> = session.iter().map(|(_, value)| csv_field(value)).collect();
+        let values = values.join(",");
+
+        let mut out = String::new();
+        writeln!(out, "{},timestamp,task,user,kind,detail", columns.join(","))?;
+        for record in records {
+            writeln!(
+                out,
+                "{},{},{},{},{},{}",
+                values,
+                record.timestamp.to_rfc3339(),
+                csv_field(record.task.as_deref().unwrap_or("")),
+                csv_field(record.user.as_deref().unwrap_or("")),
+                record.action.kind(),
+                csv_field(&record.action.detail()),
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+pub struct MarkdownReporter;
+
+impl Reporter for MarkdownReporter {
+    fn extension(&self) -> &'static str {
+        "md"
+    }
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String> {
+        let mut out = String::new();
+        writeln!(out, "# Cleanup report `{}`\n", summary.session.id)?;
+        writeln!(out, "| Session | |")?;
+        writeln!(out, "| --- | --- |")?;
+        for (field, value) in session_rows(&summary.session) {
+            writeln!(out, "| {} | {} |", field, markdown_cell(&value))?;
+        }
+
+        writeln!(out, "\n| Metric | Value |")?;
+        writeln!(out, "| --- | --- |")?;
+        for (metric, value) in summary_rows(summary) {
+            writeln!(out, "| {} | {} |", metric, markdown_cell(&value))?;
+        }
+
+        writeln!(out, "\n## Actions\n")?;
+        writeln!(out, "| Time | Kind | Detail |")?;
+        writeln!(out, "| --- | --- | --- |")?;
+        for record in records {
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                record.action.kind(),
+                markdown_cell(&record.action.detail()),
+            )?;
+        }
+        Ok(out)
+    }
+}
+
+/// A single HTML file with inline styles, safe to attach to a ticket.
+pub struct HtmlReporter;
+
+impl Reporter for HtmlReporter {
+    fn extension(&self) -> &'static str {
+        "html"
+    }
+
+    fn render(&self, records: &[ActionRecord], summary: &RunSummary) -> Result<String> {
+        let mut out = String::new();
+        writeln!(out, "<!DOCTYPE html>")?;
+        writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+        writeln!(out, "<title>Cleanup report {}</title>", summary.session.id)?;
+        writeln!(out, "<style>{}</style>", HTML_STYLE)?;
+        writeln!(out, "</head><body>")?;
+        writeln!(out, "<h1>Cleanup report <code>{}</code></h1>", summary.session.id)?;
+
+        writeln!(out, "<table><tr><th>Session</th><th></th></tr>")?;
+        for (field, value) in session_rows(&summary.session) {
+            writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", field, html_escape(&value))?;
+        }
+        writeln!(out, "</table>")?;
+
+        writeln!(out, "<table><tr><th>Metric</th><th>Value</th></tr>")?;
+        for (metric, value) in summary_rows(summary) {
+            writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", metric, html_escape(&value))?;
+        }
+        writeln!(out, "</table>")?;
+
+        writeln!(out, "<h2>Actions</h2>")?;
+        writeln!(out, "<table><tr><th>Time</th><th>Kind</th><th>Detail</th></tr>")?;
+        for record in records {
+            let class = if record.action.error_kind().is_some() { " class=\"error\"" } else { "" };
+            writeln!(
+                out,
+                "<tr{}><td>{}</td><td>{}</td><td>{}</td></tr>",
+                class,
+                record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                record.action.kind(),
+                html_escape(&record.action.detail()),
+            )?;
+        }
+        writeln!(out, "</table>")?;
+        writeln!(out, "</body></html>")?;
+        Ok(out)
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:4px 8px;text-align:left;font-size:14px}\
+th{background:#f0f0f0}tr.error td{background:#fde8e8}";
+
+fn session_rows(session: &Session) -> Vec<(&'static str, String)> {
+    let time = |t: &chrono::DateTime<chrono::Utc>| t.to_rfc3339();
+    vec![
+        ("Session", session.id.to_string()),
+        ("Status", format!("{:?}", session.status)),
+        ("Resumed", session.resumed.to_string()),
+        ("Dry run", session.dry_run.to_string()),
+        ("Started", time(&session.started_at)),
+        ("Ended", session.ended_at.as_ref().map(time).unwrap_or_default()),
+        ("Host", session.host.hostname.clone()),
+        ("OS", format!("{} {} {}", session.host.os, session.host.arch, session.host.kernel.as_deref().unwrap_or("")).trim_end().to_string()),
+        ("User", match session.uid {
+            Some(uid) => format!("{} (euid {})", session.user, uid),
+            None => session.user.clone(),
+        }),
+        ("Plan hash", session.plan_hash.clone()),
+        ("Tool version", session.tool_version.clone()),
+    ]
+}
+
+fn summary_rows(summary: &RunSummary) -> Vec<(&'static str, String)> {
+    let errors = summary
+        .errors_by_kind
+        .iter()
+        .map(|(kind, count)| format!("{}: {}", kind, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    vec![
+        ("Elapsed", format!("{} ms", summary.elapsed_ms)),
+        ("Actions recorded", summary.actions.to_string()),
+        ("Files removed", summary.files_removed.to_string()),
+        ("Bytes freed", format_bytes(summary.bytes_freed)),
+        ("Bytes overwritten", format_bytes(summary.bytes_overwritten)),
+        ("Bytes reclaimable (dry run)", format_bytes(summary.bytes_planned)),
+        ("Errors", if errors.is_empty() { "0".to_string() } else { format!("{} ({})", summary.errors(), errors) }),
+    ]
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn markdown_cell(value: &str) -> String {
+    value.replace('|', "\\|").replace('\n', " ")
+}
+
+fn html_escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::net_mon::{Action, ActionStats};
+
+    fn summary() -> RunSummary {
+        let mut session = Session::start(None, "abc123".to_string());
+        session.user = "ops, team".to_string();
+        RunSummary::new(session, Duration::from_millis(1500), &ActionStats::default())
+    }
+
+    fn records() -> Vec<ActionRecord> {
+        vec![
+            ActionRecord { timestamp: Utc::now(), task: Some("tmp".to_string()), user: None, action: Action::FileDeleted("/tmp/a,b".to_string()) },
+            ActionRecord {
+                timestamp: Utc::now(),
+                task: Some("homes".to_string()),
+                user: Some("alice".to_string()),
+                action: Action::TargetFailed("/home/alice/x".to_string(), "said \"no\"".to_string()),
+            },
+        ]
+    }
+
+    #[test]
+    fn csv_carries_the_session_in_columns() {
+        let summary = summary();
+        let csv = CsvReporter.render(&records(), &summary).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| !line.starts_with('#')));
+        assert!(lines[0].starts_with("session,session_status,session_resumed,session_dry_run,"));
+        assert!(lines[0].ends_with(",session_plan_hash,session_tool_version,timestamp,task,user,kind,detail"));
+        let session = format!("{},Running,false,false,", summary.session.id);
+        assert!(lines[1].starts_with(&session), "{}", lines[1]);
+        assert!(lines[1].contains(",\"ops, team (euid ") || lines[1].contains(",\"ops, team\","), "{}", lines[1]);
+        assert!(lines[1].ends_with(",tmp,,FileDeleted,\"/tmp/a,b\""), "{}", lines[1]);
+        assert!(lines[2].ends_with(",homes,alice,TargetFailed,\"/home/alice/x: said \"\"no\"\"\""), "{}", lines[2]);
+    }
+
+    #[test]
+    fn json_round_trips_as_a_report() {
+        let summary = summary();
+        let json = JsonReporter.render(&records(), &summary).unwrap();
+        let report: JsonReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.session.id, summary.session.id);
+        assert_eq!(report.actions.len(), 2);
+        assert_eq!(report.actions[1].user.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn json_lines_start_with_the_session() {
+        let summary = summary();
+        let jsonl = JsonLinesReporter.render(&records(), &summary).unwrap();
+        let lines: Vec<serde_json::Value> = jsonl.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["session"]["id"], summary.session.id.to_string());
+        assert_eq!(lines[1]["task"], "tmp");
+        assert_eq!(lines[2]["action"]["TargetFailed"][0], "/home/alice/x");
+    }
+
+    #[test]
+    fn markdown_and_html_escape_their_cells() {
+        let summary = summary();
+        let mut records = records();
+        records.push(ActionRecord { timestamp: Utc::now(), task: None, user: None, action: Action::Error("a|b <c> & 'd'".to_string()) });
+
+        let markdown = MarkdownReporter.render(&records, &summary).unwrap();
+        assert!(markdown.starts_with(&format!("# Cleanup report `{}`", summary.session.id)));
+        assert!(markdown.contains("| Error | a\\|b <c> & 'd' |"), "{}", markdown);
+
+        let html = HtmlReporter.render(&records, &summary).unwrap();
+        assert!(html.contains("<td>a|b &lt;c&gt; &amp; &#39;d&#39;</td>"), "{}", html);
+        assert_eq!(html.matches("<tr class=\"error\">").count(), 2);
+        assert!(html.trim_end().ends_with("</body></html>"));
+    }
+}
@@ -0,0 +1,199 @@
+//! `report diff <old> <new>`: compares the action reports of two sessions
+//! to spot regressions in recurring cleanup jobs.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::net_mon::{Action, ActionRecord};
+use crate::report::JsonReport;
+use crate::utils::{read_report_index, format_bytes};
+
+/// Where the tasks sent no task name (older reports).
+const UNKNOWN_TASK: &str = "(untagged)";
+
+#[derive(Debug, Serialize)]
+pub struct ReportDiff {
+    pub old: PathBuf,
+    pub new: PathBuf,
+    /// Cleaned in the new session but not in the old one.
+    pub newly_cleaned: Vec<String>,
+    /// Failed in the new session but not in the old one.
+    pub newly_failed: Vec<String>,
+    /// Freed bytes per task as `(old, new)`.
+    pub freed_by_task: BTreeMap<String, (u64, u64)>,
+}
+
+/// Accepts either a report path or a session id, which is looked up in the
+/// report index.
+pub fn resolve_report(arg: &str) -> Result<PathBuf> {
+    let path = Path::new(arg);
+    if path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    let session_id: Uuid = arg
+        .parse()
+        .with_context(|| format!("{} is neither a report file nor a session id", arg))?;
+    read_report_index()?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.session_id == session_id && entry.format == "json" && entry.name == "cleanup_actions")
+        .map(|entry| entry.path)
+        .with_context(|| format!("No JSON action report indexed for session {}", session_id))
+}
+
+/// Reads the actions of a JSON report. Reports from before the session
+/// header are a bare array and are accepted too.
+pub fn load_report(path: &Path) -> Result<Vec<ActionRecord>> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read report: {}", path.display()))?;
+    if let Ok(report) = serde_json::from_str::<JsonReport>(&json) {
+        return Ok(report.actions);
+    }
+    serde_json::from_str(&json)
+        .with_context(|| format!("Not a JSON action report: {}", path.display()))
+}
+
+pub fn diff_reports(old: &str, new: &str) -> Result<ReportDiff> {
+    let old_path = resolve_report(old)?;
+    let new_path = resolve_report(new)?;
+    if old_path == new_path {
+        bail!("Both arguments resolve to {}", old_path.display());
+    }
+    let old_outcome = Outcome::from_records(&load_report(&old_path)?);
+    let new_outcome = Outcome::from_records(&load_report(&new_path)?);
+
+    let mut freed_by_task: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for (task, bytes) in &old_outcome.freed_by_task {
+        freed_by_task.entry(task.clone()).or_default().0 = *bytes;
+    }
+    for (task, bytes) in &new_outcome.freed_by_task {
+        freed_by_task.entry(task.clone()).or_default().1 = *bytes;
+    }
+
+    Ok(ReportDiff {
+        old: old_path,
+        new: new_path,
+        newly_cleaned: new_outcome.cleaned.difference(&old_outcome.cleaned).cloned().collect(),
+        newly_failed: new_outcome.failed.difference(&old_outcome.failed).cloned().collect(),
+        freed_by_task,
+    })
+}
+
+/// Per-target result of one session.
+#[derive(Default)]
+struct Outcome {
+    cleaned: BTreeSet<String>,
+    failed: BTreeSet<String>,
+    freed_by_task: BTreeMap<String, u64>,
+}
+
+impl Outcome {
+    fn from_records(records: &[ActionRecord]) -> Self {
+        let mut outcome = Self::default();
+        for record in records {
+            match &record.action {
+                Action::TargetFailed(target, _) => {
+                    outcome.failed.insert(target.clone());
+                }
+                Action::SpaceFreed(_, bytes) => {
+                    let task = record.task.clone().unwrap_or_else(|| UNKNOWN_TASK.to_string());
+                    *outcome.freed_by_task.entry(task).or_insert(0) += bytes;
+                }
+                action => {
+                    if let Some(target) = action.completed_target() {
+                        outcome.cleaned.insert(target.to_string());
+                    }
+                }
+            }
+        }
+        // A retry that succeeded later in the same session is not a failure.
+        let cleaned = &outcome.cleaned;
+        outcome.failed.retain(|target| !cleaned.contains(target));
+        outcome
+    }
+}
+
+impl fmt::Display for ReportDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Old: {}", self.old.display())?;
+        writeln!(f, "New: {}", self.new.display())?;
+
+        writeln!(f, "\nNewly cleaned ({})", self.newly_cleaned.len())?;
+        for target in &self.newly_cleaned {
+            writeln!(f, "  + {}", target)?;
+        }
+
+        writeln!(f, "\nNewly failed ({})", self.newly_failed.len())?;
+        for target in &self.newly_failed {
+            writeln!(f, "  ! {}", target)?;
+        }
+
+        writeln!(f, "\n{:<30}{:>14}{:>14}{:>16}", "Freed by task", "old", "new", "change")?;
+        for (task, (old, new)) in &self.freed_by_task {
+            let change = if new >= old {
+                format!("+{}", format_bytes(new - old))
+            } else {
+                format!("-{}", format_bytes(old - new))
+            };
+            writeln!(f, "{:<30}{:>14}{:>14}{:>16}", task, format_bytes(*old), format_bytes(*new), change)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use crate::session::Session;
+
+    fn record(task: Option<&str>, action: Action) -> ActionRecord {
+        ActionRecord { timestamp: Utc::now(), task: task.map(str::to_string), user: None, action }
+    }
+
+    fn failed(target: &str) -> Action {
+        Action::TargetFailed(target.to_string(), "Permission denied".to_string())
+    }
+
+    #[test]
+    fn diff_reports_classifies_targets() {
+        let dir = std::env::temp_dir().join(format!("diff-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        // An old, bare-array report.
+        let old = vec![
+            record(Some("tmp"), Action::FileDeleted("/tmp/a".to_string())),
+            record(Some("tmp"), Action::SpaceFreed("/tmp/a".to_string(), 100)),
+            record(Some("logs"), failed("/var/log/x.1")),
+            record(None, Action::SpaceFreed("/old".to_string(), 7)),
+        ];
+        let new = JsonReport {
+            session: Session::start(None, String::new()),
+            actions: vec![
+                record(Some("tmp"), Action::FileDeleted("/tmp/a".to_string())),
+                record(Some("tmp"), Action::FileDeleted("/tmp/b".to_string())),
+                record(Some("tmp"), Action::SpaceFreed("/tmp/b".to_string(), 300)),
+                record(Some("logs"), failed("/var/log/x.1")),
+                record(Some("logs"), failed("/var/log/y.1")),
+                // Failed, then cleaned on a retry.
+                record(Some("logs"), failed("/var/log/z.1")),
+                record(Some("logs"), Action::FileDeleted("/var/log/z.1".to_string())),
+            ],
+        };
+        let (old_path, new_path) = (dir.join("old.json"), dir.join("new.json"));
+        fs::write(&old_path, serde_json::to_string(&old).unwrap()).unwrap();
+        fs::write(&new_path, serde_json::to_string(&new).unwrap()).unwrap();
+
+        let diff = diff_reports(old_path.to_str().unwrap(), new_path.to_str().unwrap()).unwrap();
+        assert_eq!(diff.newly_cleaned, ["/tmp/b", "/var/log/z.1"]);
+        assert_eq!(diff.newly_failed, ["/var/log/y.1"]);
+        let freed: Vec<(&str, (u64, u64))> = diff.freed_by_task.iter().map(|(task, freed)| (task.as_str(), *freed)).collect();
+        assert_eq!(freed, [(UNKNOWN_TASK, (7, 0)), ("tmp", (100, 300))]);
+
+        assert!(diff_reports(old_path.to_str().unwrap(), old_path.to_str().unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Confirmation before a destructive run. Without `--yes`, `run` sizes up
+//! the plan as a dry run would, shows each task's method and targets, and
+//! asks which tasks to run. `--yes` skips the prompt, but only for a plan
+//! whose hash matches a dry run done before, found through the report
+//! index.
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use anyhow::{Result, Context, bail};
+use log::info;
+use uuid::Uuid;
+
+use crate::journal::ResumeState;
+use crate::net_mon::{Action, NetMon};
+use crate::plan::Plan;
+use crate::privileges::{join, Capability, Privileges};
+use crate::session::SessionStatus;
+use crate::summary::RunSummary;
+use crate::utils::{format_bytes, read_report_index, ReportIndexEntry};
+use crate::{run_task, timed};
+
+/// The dry run of a plan with `plan_hash` that `--yes` relies on: the
+/// newest one that completed, if any.
+pub fn reviewed_dry_run(plan_hash: &str) -> Result<Option<Uuid>> {
+    Ok(reviewed_in(&read_report_index()?, plan_hash))
+}
+
+/// `reviewed_dry_run` over the given index entries.
+fn reviewed_in(entries: &[ReportIndexEntry], plan_hash: &str) -> Option<Uuid> {
+    let summaries = entries.iter().filter(|entry| entry.name == "cleanup_summary" && entry.format == "json");
+    for entry in summaries.rev() {
+        // Reports may have been cleaned up since.
+        let Ok(json) = fs::read_to_string(&entry.path) else { continue };
+        let Ok(summary) = serde_json::from_str::<RunSummary>(&json) else { continue };
+        let session = &summary.session;
+        if session.dry_run
+            && session.plan_hash == plan_hash
+            && matches!(session.status, SessionStatus::Completed | SessionStatus::CompletedWithErrors)
+        {
+            return Some(session.id);
+        }
+    }
+    None
+}
+
+/// What one task would do, as shown for review.
+#[derive(Debug, Clone)]
+pub struct TaskReview {
+    /// Index of the task in the plan.
+    pub index: usize,
+    pub name: String,
+    pub method: &'static str,
+    pub targets: Vec<(String, u64)>,
+    /// Why the targets could not be sized up, if they couldn't.
+    pub error: Option<String>,
+    /// Capabilities the task needs that the process lacks.
+    pub missing: Vec<Capability>,
+    pub pre: Vec<String>,
+    pub post: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl fmt::Display for TaskReview {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: u64 = self.targets.iter().map(|(_, size)| size).sum();
+        writeln!(f, "{}: {} ({})", self.name, self.method, format_bytes(total))?;
+        for (target, size) in &self.targets {
+            writeln!(f, "    {:>10}  {}", format_bytes(*size), target)?;
+        }
+        if self.targets.is_empty() && self.error.is_none() {
+            writeln!(f, "    nothing to do")?;
+        }
+        if !self.missing.is_empty() {
+            writeln!(f, "    will be skipped: needs {}", join(&self.missing))?;
+        }
+        if let Some(error) = &self.error {
+            writeln!(f, "    could not size up: {}", error)?;
+        }
+        if !self.after.is_empty() {
+            writeln!(f, "    after: {}", self.after.join(", "))?;
+        }
+        if !self.pre.is_empty() {
+            writeln!(f, "    pre-hooks: {}", self.pre.join(", "))?;
+        }
+        if !self.post.is_empty() {
+            writeln!(f, "    post-hooks: {}", self.post.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sizes up every task as a dry run does, without a session of its own,
+/// in the order the tasks would run.
+pub async fn review_plan(plan: &Plan, resume: &ResumeState) -> Result<Vec<TaskReview>> {
+    if plan.tasks.is_empty() {
+        return Ok(Vec::new());
+    }
+    let net_mon = NetMon::new();
+    net_mon.start_monitoring().await;
+    let deps = plan.dependencies()?;
+    let privileges = Privileges::current();
+
+    let mut reviews = Vec::new();
+    for task in plan.ordered()? {
+        let index = plan.tasks.iter().position(|t| t.name == task.name).expect("task is in its plan");
+        let result = timed(&net_mon, &task.name, run_task(&net_mon, resume, task, true)).await;
+        reviews.push(TaskReview {
+            index,
+            name: task.name.clone(),
+            method: task.kind.method(),
+            targets: Vec::new(),
+            error: result.err().map(|e| format!("{:#}", e)),
+            missing: privileges.missing(task),
+            pre: task.pre.clone(),
+            post: task.post.clone(),
+            after: deps[index].iter().map(|&d| plan.tasks[d].name.clone()).collect(),
+        });
+    }
+
+    // Everything the sizing run sent is logged once NetMon has drained.
+    net_mon.close().await;
+    for record in net_mon.get_actions().await {
+        let (task, target, size) = match (record.task, record.action) {
+            (Some(task), Action::Planned(target, size)) => (task, target, size),
+            (Some(task), Action::StalePlanned(target, _)) => (task, target, 0),
+            _ => continue,
+        };
+        if let Some(review) = reviews.iter_mut().find(|review| review.name == task) {
+            review.targets.push((target, size));
+        }
+    }
+    Ok(reviews)
+}
+
+/// Shows the reviews and asks which tasks to run, on the terminal. Returns
+/// the plan indices of the declined tasks; a declined task's dependents
+/// are declined with it. Blocking.
+pub fn confirm(reviews: &[TaskReview], plan: &Plan) -> Result<HashSet<usize>> {
+    if !io::stdin().is_terminal() {
+        bail!("No terminal to confirm the run on; do a --dry-run of this plan first, then run it with --yes");
+    }
+    let deps = plan.dependencies()?;
+    let total: u64 = reviews.iter().flat_map(|review| &review.targets).map(|(_, size)| size).sum();
+    println!("\nPlan {} ({} tasks, {}):\n", &plan.hash()[..12], reviews.len(), format_bytes(total));
+    for review in reviews {
+        println!("{}", review);
+    }
+
+    let mut declined = HashSet::new();
+    match ask("Run these tasks? [a]ll / [e]ach / [q]uit: ", &["a", "e", "q"])?.as_str() {
+        "a" => return Ok(declined),
+        "q" => bail!("Run aborted at review"),
+        _ => {}
+    }
+
+    let mut approve_rest = false;
+    for review in reviews {
+        if let Some(&dep) = deps[review.index].iter().find(|d| declined.contains(d)) {
+            println!("Skipping {}: it runs after declined {}", review.name, plan.tasks[dep].name);
+            declined.insert(review.index);
+            continue;
+        }
+        if approve_rest {
+            continue;
+        }
+        let question = format!("Run {}? [y]es / [n]o / [a]ll remaining / [q]uit: ", review.name);
+        match ask(&question, &["y", "n", "a", "q"])?.as_str() {
+            "n" => {
+                declined.insert(review.index);
+            }
+            "a" => approve_rest = true,
+            "q" => bail!("Run aborted at review"),
+            _ => {}
+        }
+    }
+    info!("Review: {} of {} tasks approved", reviews.len() - declined.len(), reviews.len());
+    Ok(declined)
+}
+
+/// Asks until the answer's first letter is one of `choices`.
+fn ask(question: &str, choices: &[&str]) -> Result<String> {
+    let stdin = io::stdin();
+    loop {
+        print!("{}", question);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).context("Failed to read answer")? == 0 {
+            bail!("Run aborted at review: end of input");
+        }
+        let answer = line.trim().to_lowercase();
+        if let Some(choice) = choices.iter().find(|choice| answer.starts_with(**choice)) {
+            return Ok(choice.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::Duration;
+    use chrono::Utc;
+    use crate::net_mon::ActionStats;
+    use crate::session::Session;
+
+    /// Writes the summary of a finished session and returns its index
+    /// entry.
+    fn summary(dir: &Path, plan_hash: &str, dry_run: bool, status: SessionStatus) -> (Uuid, ReportIndexEntry) {
+        let mut session = Session::start(None, plan_hash.to_string());
+        session.dry_run = dry_run;
+        session.finish(status);
+        let id = session.id;
+        let path = dir.join(format!("cleanup_summary_{}.json", id));
+        let summary = RunSummary::new(session, Duration::ZERO, &ActionStats::default());
+        fs::write(&path, serde_json::to_string(&summary).unwrap()).unwrap();
+        let entry = ReportIndexEntry { session_id: id, name: "cleanup_summary".to_string(), format: "json".to_string(), path, written_at: Utc::now() };
+        (id, entry)
+    }
+
+    #[test]
+    fn yes_needs_a_completed_dry_run_of_the_same_plan() {
+        let dir = std::env::temp_dir().join(format!("review-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (reviewed, reviewed_entry) = summary(&dir, "plan-a", true, SessionStatus::CompletedWithErrors);
+        let (_, real_run) = summary(&dir, "plan-a", false, SessionStatus::Completed);
+        let (_, failed) = summary(&dir, "plan-a", true, SessionStatus::Failed);
+        let (_, other_plan) = summary(&dir, "plan-b", true, SessionStatus::Completed);
+        let (_, mut not_a_summary) = summary(&dir, "plan-a", true, SessionStatus::Completed);
+        not_a_summary.name = "cleanup_actions".to_string();
+        let mut gone = summary(&dir, "plan-a", true, SessionStatus::Completed).1;
+        fs::remove_file(&gone.path).unwrap();
+        gone.path = dir.join("removed.json");
+
+        let entries = [reviewed_entry, real_run, failed, other_plan, not_a_summary, gone];
+        assert_eq!(reviewed_in(&entries, "plan-a"), Some(reviewed));
+        assert_eq!(reviewed_in(&entries[1..], "plan-a"), None);
+        assert_eq!(reviewed_in(&entries, "plan-c"), None);
+
+        // The newest reviewed dry run wins.
+        let (newer, newer_entry) = summary(&dir, "plan-a", true, SessionStatus::Completed);
+        let entries = [entries[0].clone(), newer_entry];
+        assert_eq!(reviewed_in(&entries, "plan-a"), Some(newer));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+//! Old logs left behind by logrotate and friends, recognised by their
+//! rotation suffix: `syslog.1`, `syslog.2.gz`, `app.log-20240101`,
+//! `kern.log.old`. The live log next to them is never touched.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use log::info;
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+use crate::utils::remove_and_record;
+
+const COMPRESSED: [&str; 4] = ["gz", "xz", "bz2", "zst"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RotatedLogsTask {
+    /// Directory searched recursively, e.g. `/var/log`.
+    pub path: PathBuf,
+    /// Only remove rotated logs not modified for this many days.
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+}
+
+/// Whether a file name carries a rotation suffix, optionally compressed.
+pub fn is_rotated_log(name: &str) -> bool {
+    let mut name = name;
+    if let Some((stem, ext)) = name.rsplit_once('.') {
+        if COMPRESSED.contains(&ext) {
+            name = stem;
+        }
+    }
+    if let Some((base, suffix)) = name.rsplit_once('.') {
+        if !base.is_empty() && (suffix == "old" || (!suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()))) {
+            return true;
+        }
+    }
+    if let Some((base, suffix)) = name.rsplit_once('-') {
+        if !base.is_empty() && suffix.len() == 8 && suffix.bytes().all(|b| b.is_ascii_digit()) {
+            return true;
+        }
+    }
+    false
+}
+
+impl RotatedLogsTask {
+    /// Rotated logs old enough to remove, with their size. Blocking.
+    fn candidates(&self) -> Vec<(PathBuf, u64)> {
+        let cutoff = self
+            .older_than_days
+            .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days * 86_400)));
+        let mut out = Vec::new();
+        collect_rotated(&self.path, cutoff, &mut out);
+        out
+    }
+}
+
+fn collect_rotated(dir: &Path, cutoff: Option<SystemTime>, out: &mut Vec<(PathBuf, u64)>) {
+    let Ok(read_dir) = fs::read_dir(dir) else { return };
+    for entry in read_dir.flatten() {
+        let Ok(metadata) = entry.metadata() else { continue };
+        if metadata.is_dir() {
+            collect_rotated(&entry.path(), cutoff, out);
+            continue;
+        }
+        if !metadata.is_file() || !is_rotated_log(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let old_enough = match (cutoff, metadata.modified().ok()) {
+            (Some(cutoff), Some(modified)) => modified < cutoff,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if old_enough {
+            out.push((entry.path(), metadata.len()));
+        }
+    }
+}
+
+/// Removes (or, with `dry_run`, sizes up) the rotated logs under a dir.
+pub async fn clean_rotated_logs(net_mon: &NetMon, resume: &ResumeState, task: &RotatedLogsTask, dry_run: bool) -> Result<()> {
+    info!("Removing rotated logs under {}...", task.path.display());
+    let scan = task.clone();
+    let candidates = tokio::task::spawn_blocking(move || scan.candidates()).await?;
+    info!("{} rotated logs eligible", candidates.len());
+
+    for (path, size) in candidates {
+        let target = path.display().to_string();
+        if dry_run {
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+        remove_and_record(net_mon, &path, size).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_suffixes() {
+        for name in ["syslog.1", "syslog.2.gz", "app.log.old", "app.log-20240101", "auth.log-20240101.xz", "kern.log.10.zst"] {
+            assert!(is_rotated_log(name), "{}", name);
+        }
+        for name in ["syslog", "app.log", "archive.gz", ".1", "-20240101", "app.log-2024", "data.v2", "notes.old.txt"] {
+            assert!(!is_rotated_log(name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! `scan <path>`: disk usage of one mount as a size tree, its largest
+//! directories, and a suggested plan for what matches a known task
+//! (package/build caches, temp dirs, rotated logs, the container engine).
+//!
+//! The suggested plan is a starting point to edit, not something to run
+//! blind: age filters are filled in and everything below `--min-size` is
+//! left out.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use anyhow::{Result, Context};
+use futures::future::{join_all, BoxFuture, FutureExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::caches::{CacheKind, CacheTask};
+use crate::docker::DockerTask;
+use crate::open_files::OnOpen;
+use crate::plan::{Plan, PlanTask, TaskKind};
+use crate::rotated_logs::{is_rotated_log, RotatedLogsTask};
+use crate::utils::{format_bytes, tree_stats};
+
+/// Age filter written into suggested cache tasks.
+const SUGGESTED_CACHE_DAYS: u64 = 30;
+/// Age filter written into suggested rotated-log tasks.
+const SUGGESTED_LOG_DAYS: u64 = 14;
+/// How long a temp dir entry must be untouched to be suggested, as
+/// systemd-tmpfiles ages `/tmp`.
+const SUGGESTED_TEMP_DAYS: u64 = 10;
+const TEMP_DIRS: [&str; 2] = ["/tmp", "/var/tmp"];
+/// Temp dir entries that belong to running services (X11 and ICE sockets,
+/// systemd `PrivateTmp`), never suggested for removal.
+const TEMP_KEEP: [&str; 6] = [".X11-unix", ".ICE-unix", ".XIM-unix", ".font-unix", ".Test-unix", "snap-private-tmp"];
+const TEMP_KEEP_PREFIXES: [&str; 1] = ["systemd-private-"];
+const ENGINE_DATA_DIR: &str = "/var/lib/docker";
+
+/// Cache directories by path suffix, and how many components above the
+/// match the home directory is.
+const HOME_CACHES: [(&str, CacheKind, usize); 7] = [
+    (".cargo/registry", CacheKind::Cargo, 2),
+    (".cargo/git", CacheKind::Cargo, 2),
+    (".cache/pip", CacheKind::Pip, 2),
+    (".npm/_cacache", CacheKind::Npm, 2),
+    (".cache/yarn", CacheKind::Yarn, 2),
+    (".yarn/berry/cache", CacheKind::Yarn, 3),
+    (".cache/thumbnails", CacheKind::Thumbnails, 2),
+];
+const SYSTEM_CACHES: [(&str, CacheKind); 2] = [
+    ("/var/cache/apt", CacheKind::Apt),
+    ("/var/cache/dnf", CacheKind::Dnf),
+];
+
+/// A directory and the bytes allocated below it.
+#[derive(Debug)]
+pub struct DirNode {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Bytes in files with a rotation suffix (`syslog.1`, `app.log-20240101`).
+    pub rotated_log_bytes: u64,
+    /// Whether the dir holds a `CACHEDIR.TAG`.
+    pub cache_tagged: bool,
+    pub children: Vec<DirNode>,
+}
+
+impl DirNode {
+    fn descendants(&self) -> Vec<&DirNode> {
+        let mut out = Vec::new();
+        let mut stack: Vec<&DirNode> = self.children.iter().collect();
+        while let Some(node) = stack.pop() {
+            out.push(node);
+            stack.extend(node.children.iter());
+        }
+        out
+    }
+
+    fn rotated_log_bytes_total(&self) -> u64 {
+        self.rotated_log_bytes + self.children.iter().map(|c| c.rotated_log_bytes_total()).sum::<u64>()
+    }
+}
+
+/// Entries of one directory, read in a blocking task.
+struct DirListing {
+    file_bytes: u64,
+    rotated_log_bytes: u64,
+    cache_tagged: bool,
+    subdirs: Vec<PathBuf>,
+}
+
+fn list_dir(path: &Path, device: u64) -> DirListing {
+    let mut listing = DirListing { file_bytes: 0, rotated_log_bytes: 0, cache_tagged: false, subdirs: Vec::new() };
+    let Ok(read_dir) = fs::read_dir(path) else { return listing };
+    for entry in read_dir.flatten() {
+        let Ok(metadata) = entry.metadata() else { continue };
+        if metadata.is_dir() {
+            // Stay on the scanned mount.
+            if metadata.dev() == device {
+                listing.subdirs.push(entry.path());
+            }
+            continue;
+        }
+        let bytes = metadata.blocks() * 512;
+        listing.file_bytes += bytes;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name == "CACHEDIR.TAG" {
+            listing.cache_tagged = true;
+        } else if metadata.is_file() && is_rotated_log(&name) {
+            listing.rotated_log_bytes += bytes;
+        }
+    }
+    listing
+}
+
+/// Builds the size tree of `root`, reading up to `parallelism` directories
+/// at once. Symlinks are not followed and other mounts are skipped.
+pub async fn walk(root: &Path, parallelism: usize) -> Result<DirNode> {
+    let root = fs::canonicalize(root).with_context(|| format!("Cannot scan {}", root.display()))?;
+    let device = fs::metadata(&root)?.dev();
+    let permits = Arc::new(Semaphore::new(parallelism.max(1)));
+    let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+    Ok(walk_dir(root, device, permits, permit).await)
+}
+
+/// Walks `path` once `permit`, taken before the task was spawned, lets it
+/// list the dir: at most `parallelism` dirs are listed or waiting to be.
+fn walk_dir(path: PathBuf, device: u64, permits: Arc<Semaphore>, permit: OwnedSemaphorePermit) -> BoxFuture<'static, DirNode> {
+    async move {
+        let listing = {
+            let _permit = permit;
+            let dir = path.clone();
+            tokio::task::spawn_blocking(move || list_dir(&dir, device))
+                .await
+                .expect("directory listing panicked")
+        };
+        let mut walks = Vec::with_capacity(listing.subdirs.len());
+        for dir in listing.subdirs {
+            let permit = permits.clone().acquire_owned().await.expect("semaphore is never closed");
+            walks.push(tokio::spawn(walk_dir(dir, device, permits.clone(), permit)));
+        }
+        let children = join_all(walks)
+            .await
+            .into_iter()
+            .filter_map(|child| child.ok())
+            .collect::<Vec<_>>();
+        DirNode {
+            size: listing.file_bytes + children.iter().map(|c| c.size).sum::<u64>(),
+            path,
+            rotated_log_bytes: listing.rotated_log_bytes,
+            cache_tagged: listing.cache_tagged,
+            children,
+        }
+    }
+    .boxed()
+}
+
+/// A suggested task and the bytes it would free at scan time.
+#[derive(Debug)]
+pub struct Suggestion {
+    pub task: PlanTask,
+    pub size: u64,
+    pub matched: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct ScanResult {
+    pub root: PathBuf,
+    pub total: u64,
+    pub largest: Vec<(PathBuf, u64)>,
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl ScanResult {
+    pub fn plan(&self) -> Plan {
+        Plan { tasks: self.suggestions.iter().map(|s| s.task.clone()).collect(), ..Plan::default() }
+    }
+}
+
+pub async fn scan(root: &Path, top: usize, min_size: u64, parallelism: usize) -> Result<ScanResult> {
+    let tree = walk(root, parallelism).await?;
+
+    let mut largest: Vec<(PathBuf, u64)> = tree.descendants().iter().map(|n| (n.path.clone(), n.size)).collect();
+    largest.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    largest.truncate(top);
+
+    // Temp entries are aged with `tree_stats`, another walk.
+    let (tree, mut suggestions) = tokio::task::spawn_blocking(move || {
+        let suggestions = suggest(&tree);
+        (tree, suggestions)
+    })
+    .await?;
+    suggestions.retain(|s| s.size >= min_size);
+    suggestions.sort_by_key(|s| std::cmp::Reverse(s.size));
+
+    Ok(ScanResult { root: tree.path.clone(), total: tree.size, largest, suggestions })
+}
+
+/// Per-kind cache matches, merged into one task per cache kind.
+#[derive(Default)]
+struct CacheMatch {
+    homes: Vec<PathBuf>,
+    target_roots: Vec<PathBuf>,
+    size: u64,
+    matched: Vec<PathBuf>,
+}
+
+fn suggest(tree: &DirNode) -> Vec<Suggestion> {
+    let mut caches: BTreeMap<&'static str, (CacheKind, CacheMatch)> = BTreeMap::new();
+    let mut suggestions = Vec::new();
+    let mut stack: Vec<&DirNode> = tree.children.iter().collect();
+
+    while let Some(node) = stack.pop() {
+        let path = node.path.as_path();
+        let mut cache_match = |kind: CacheKind, home: Option<PathBuf>, target_root: Option<PathBuf>| {
+            let (_, entry) = caches.entry(cache_name(kind)).or_insert_with(|| (kind, CacheMatch::default()));
+            entry.homes.extend(home.filter(|h| !entry.homes.contains(h)));
+            entry.target_roots.extend(target_root);
+            entry.size += node.size;
+            entry.matched.push(node.path.clone());
+        };
+
+        if let Some((_, kind, up)) = HOME_CACHES.iter().find(|(suffix, ..)| path.ends_with(suffix)) {
+            cache_match(*kind, path.ancestors().nth(*up).map(Path::to_path_buf), None);
+            continue;
+        }
+        if let Some((_, kind)) = SYSTEM_CACHES.iter().find(|(dir, _)| path == Path::new(dir)) {
+            cache_match(*kind, None, None);
+            continue;
+        }
+        if node.cache_tagged && path.file_name().is_some_and(|n| n == "target") {
+            cache_match(CacheKind::Cargo, None, path.parent().map(Path::to_path_buf));
+            continue;
+        }
+        if path.parent().is_some_and(|parent| TEMP_DIRS.iter().any(|t| parent == Path::new(t))) {
+            // `RemoveTempFiles` has no age filter: only suggest entries
+            // nothing has touched for a while.
+            if !is_service_temp(path) && untouched_for(path, SUGGESTED_TEMP_DAYS) {
+                suggestions.push(Suggestion {
+                    task: PlanTask::new(
+                        &task_name("temp", path),
+                        TaskKind::RemoveTempFiles { path: path.display().to_string(), on_open: OnOpen::default() },
+                    ),
+                    size: node.size,
+                    matched: vec![node.path.clone()],
+                });
+            }
+            continue;
+        }
+        if path == Path::new(ENGINE_DATA_DIR) {
+            suggestions.push(Suggestion {
+                task: PlanTask::new(
+                    "docker",
+                    TaskKind::Docker(DockerTask { older_than_hours: Some(SUGGESTED_CACHE_DAYS * 24), ..DockerTask::default() }),
+                ),
+                size: node.size,
+                matched: vec![node.path.clone()],
+            });
+            continue;
+        }
+        // Only count rotation suffixes inside log dirs: elsewhere `.1`
+        // is as likely a shared library version.
+        if path.file_name().is_some_and(|n| n == "log" || n == "logs") {
+            let rotated = node.rotated_log_bytes_total();
+            if rotated > 0 {
+                suggestions.push(Suggestion {
+                    task: PlanTask::new(
+                        &task_name("rotated_logs", path),
+                        TaskKind::RotatedLogs(RotatedLogsTask {
+                            path: node.path.clone(),
+                            older_than_days: Some(SUGGESTED_LOG_DAYS),
+                        }),
+                    ),
+                    size: rotated,
+                    matched: vec![node.path.clone()],
+                });
+            }
+            continue;
+        }
+        stack.extend(node.children.iter());
+    }
+
+    for (name, (kind, found)) in caches {
+        suggestions.push(Suggestion {
+            task: PlanTask::new(
+                name,
+                TaskKind::Cache(CacheTask {
+                    cache: kind,
+                    older_than_days: Some(SUGGESTED_CACHE_DAYS),
+                    homes: found.homes,
+                    target_roots: found.target_roots,
+                }),
+            ),
+            size: found.size,
+            matched: found.matched,
+        });
+    }
+    suggestions
+}
+
+/// Sockets and `PrivateTmp` dirs of running services.
+fn is_service_temp(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else { return false };
+    TEMP_KEEP.contains(&name) || TEMP_KEEP_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Whether nothing below `path` was modified in the last `days` days.
+fn untouched_for(path: &Path, days: u64) -> bool {
+    let cutoff = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+    match tree_stats(path) {
+        (_, Some(newest)) => newest < cutoff,
+        (_, None) => false,
+    }
+}
+
+fn cache_name(kind: CacheKind) -> &'static str {
+    match kind {
+        CacheKind::Cargo => "cache_cargo",
+        CacheKind::Pip => "cache_pip",
+        CacheKind::Npm => "cache_npm",
+        CacheKind::Yarn => "cache_yarn",
+        CacheKind::Apt => "cache_apt",
+        CacheKind::Dnf => "cache_dnf",
+        CacheKind::Thumbnails => "cache_thumbnails",
+    }
+}
+
+/// `prefix` plus the path with every non-alphanumeric run as `_`; unique
+/// as long as paths are.
+fn task_name(prefix: &str, path: &Path) -> String {
+    let mut name = prefix.to_string();
+    let mut last_underscore = false;
+    for c in path.display().to_string().chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c);
+            last_underscore = false;
+        } else if !last_underscore {
+            name.push('_');
+            last_underscore = true;
+        }
+    }
+    name.trim_end_matches('_').to_string()
+}
+
+impl fmt::Display for ScanResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} uses {}", self.root.display(), format_bytes(self.total))?;
+
+        writeln!(f, "\nLargest directories")?;
+        for (path, size) in &self.largest {
+            writeln!(f, "{:>12}  {}", format_bytes(*size), path.display())?;
+        }
+
+        writeln!(f, "\nSuggested tasks ({})", self.suggestions.len())?;
+        for suggestion in &self.suggestions {
+            writeln!(f, "{:>12}  {}", format_bytes(suggestion.size), suggestion.task.name)?;
+            for path in &suggestion.matched {
+                writeln!(f, "{:>12}  - {}", "", path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn service_temp_dirs_are_kept() {
+        assert!(is_service_temp(Path::new("/tmp/.X11-unix")));
+        assert!(is_service_temp(Path::new("/tmp/systemd-private-0123abcd-nginx.service-XyZ")));
+        assert!(!is_service_temp(Path::new("/tmp/build-1234")));
+    }
+
+    #[tokio::test]
+    async fn walk_with_one_permit_visits_every_dir() {
+        let dir = std::env::temp_dir().join(format!("scan-test-{}", uuid::Uuid::new_v4()));
+        for sub in ["a/b/c", "a/d", "e"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("file"), vec![1; 8192]).unwrap();
+        }
+        let tree = walk(&dir, 1).await.unwrap();
+        assert_eq!(tree.descendants().len(), 5);
+        assert!(tree.size >= 3 * 8192);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fresh_temp_entries_are_not_suggested() {
+        let dir = std::env::temp_dir().join(format!("scan-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), b"x").unwrap();
+        assert!(!untouched_for(&dir, SUGGESTED_TEMP_DAYS));
+        assert!(!untouched_for(&dir.join("missing"), SUGGESTED_TEMP_DAYS));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
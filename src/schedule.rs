@@ -0,0 +1,86 @@
+//! Per-task schedules for daemon mode: a cron expression or a fixed
+//! interval, set with `"schedule"` on a plan task.
+use std::str::FromStr;
+use anyhow::{Result, Context, bail};
+use chrono::{DateTime, Local, Utc};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    /// Cron expression in local time: the usual 5 fields (minute first),
+    /// or 6-7 fields with seconds first.
+    Cron(String),
+    /// Fixed interval in seconds, counted from daemon start.
+    EverySecs(u64),
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Schedule::Cron(expr) => {
+                parse_cron(expr)?;
+            }
+            Schedule::EverySecs(0) => bail!("Schedule interval must be at least one second"),
+            Schedule::EverySecs(_) => {}
+        }
+        Ok(())
+    }
+
+    /// First run strictly after `after`; `None` if there is none.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Cron(expr) => {
+                let schedule = parse_cron(expr).ok()?;
+                let next = schedule.after(&after.with_timezone(&Local)).next()?;
+                Some(next.with_timezone(&Utc))
+            }
+            Schedule::EverySecs(secs) => Some(after + chrono::Duration::seconds(*secs as i64)),
+        }
+    }
+}
+
+fn parse_cron(expr: &str) -> Result<cron::Schedule> {
+    // The cron crate wants a seconds field; add one to 5-field expressions.
+    let full = if expr.split_whitespace().count() == 5 {
+        format!("0 {}", expr)
+    } else {
+        expr.to_string()
+    };
+    cron::Schedule::from_str(&full).with_context(|| format!("Invalid cron expression: {}", expr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    #[test]
+    fn five_field_cron_gets_a_seconds_field() {
+        let schedule = Schedule::Cron("30 3 * * *".to_string());
+        schedule.validate().unwrap();
+        let after = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let next = schedule.next_after(after).unwrap().with_timezone(&Local);
+        assert_eq!((next.hour(), next.minute(), next.second()), (3, 30, 0));
+        assert!(next > after);
+    }
+
+    #[test]
+    fn six_field_cron_keeps_its_seconds() {
+        let schedule = Schedule::Cron("15 */10 * * * *".to_string());
+        let after = Local.with_ymd_and_hms(2024, 5, 1, 12, 0, 20).unwrap().with_timezone(&Utc);
+        let next = schedule.next_after(after).unwrap().with_timezone(&Local);
+        assert_eq!((next.hour(), next.minute(), next.second()), (12, 10, 15));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for expr in ["", "61 * * * *", "* * * *", "every day"] {
+            let error = Schedule::Cron(expr.to_string()).validate().unwrap_err();
+            assert!(error.to_string().starts_with("Invalid cron expression"), "{}: {}", expr, error);
+        }
+        assert!(Schedule::EverySecs(0).validate().is_err());
+        let after = Utc::now();
+        assert_eq!(Schedule::EverySecs(90).next_after(after), Some(after + chrono::Duration::seconds(90)));
+    }
+}
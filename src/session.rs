@@ -0,0 +1,93 @@
+//! One invocation of the tool, serialized into the header of every report.
+use std::env;
+use std::fs;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Running,
+    Completed,
+    CompletedWithErrors,
+    /// Stopped by Ctrl-C or SIGTERM before every task finished.
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostInfo {
+    pub hostname: String,
+    pub os: String,
+    pub arch: String,
+    pub kernel: Option<String>,
+}
+
+impl HostInfo {
+    pub fn current() -> Self {
+        let hostname = read_proc("/proc/sys/kernel/hostname")
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "unknown".to_string());
+        Self {
+            hostname,
+            os: env::consts::OS.to_string(),
+            arch: env::consts::ARCH.to_string(),
+            kernel: read_proc("/proc/sys/kernel/osrelease"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: Uuid,
+    /// Set when this run continues an interrupted one with the same id.
+    pub resumed: bool,
+    /// Plan mode: targets were sized up, nothing was changed.
+    #[serde(default)]
+    pub dry_run: bool,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub host: HostInfo,
+    /// The user behind the run; with sudo, the user who invoked sudo.
+    pub user: String,
+    pub uid: Option<u32>,
+    pub plan_hash: String,
+    pub tool_version: String,
+    pub status: SessionStatus,
+}
+
+impl Session {
+    /// Starts a new session, or continues `resume` if given.
+    pub fn start(resume: Option<Uuid>, plan_hash: String) -> Self {
+        Self {
+            id: resume.unwrap_or_else(Uuid::new_v4),
+            resumed: resume.is_some(),
+            dry_run: false,
+            started_at: Utc::now(),
+            ended_at: None,
+            host: HostInfo::current(),
+            user: invoking_user(),
+            uid: Some(unsafe { libc::geteuid() }),
+            plan_hash,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            status: SessionStatus::Running,
+        }
+    }
+
+    pub fn finish(&mut self, status: SessionStatus) {
+        self.ended_at = Some(Utc::now());
+        self.status = status;
+    }
+}
+
+fn invoking_user() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn read_proc(path: &str) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
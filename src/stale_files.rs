@@ -0,0 +1,280 @@
+//! Stale `.lock` and `.pid` files and orphaned unix sockets left in run
+//! directories by processes that died without cleaning up.
+//!
+//! Nothing is removed on its name alone: a pid file is stale when its pid
+//! no longer exists, a lock file when its pid is gone or, without one, when
+//! no process holds a lock on it or has it open, and a socket when it
+//! refuses connections. Each removal records why.
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+use crate::open_files::{open_files, Holder};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StaleFilesTask {
+    /// Run directories to look in, e.g. `/run`, `/var/lock`, `/tmp`.
+    pub dirs: Vec<PathBuf>,
+    /// How many levels below each dir to look.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Leave files younger than this alone; they may be mid-setup.
+    #[serde(default = "default_min_age_secs")]
+    pub min_age_secs: u64,
+}
+
+fn default_max_depth() -> usize {
+    2
+}
+
+fn default_min_age_secs() -> u64 {
+    60
+}
+
+/// What a candidate is, which decides how staleness is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Pid,
+    Lock,
+    Socket,
+}
+
+impl Kind {
+    /// Why `path`, a file of this kind, is stale; `None` if it is in use.
+    /// `open` is filled with every process's open files the first time a
+    /// lock file needs them. Blocking.
+    fn stale(self, path: &Path, open: &mut Option<HashMap<PathBuf, Vec<Holder>>>) -> Option<String> {
+        match self {
+            Kind::Pid => pid_file_stale(path),
+            Kind::Lock => lock_file_stale(path, open.get_or_insert_with(open_files)),
+            Kind::Socket => socket_stale(path),
+        }
+    }
+}
+
+impl StaleFilesTask {
+    /// Stale files under the task's dirs, with their kind and why each is
+    /// stale. Blocking.
+    fn find(&self) -> Vec<(PathBuf, Kind, String)> {
+        let min_age = Duration::from_secs(self.min_age_secs);
+        let mut candidates = Vec::new();
+        for dir in &self.dirs {
+            collect(dir, self.max_depth, min_age, &mut candidates);
+        }
+        // Only read every process's fds if a lock file needs it.
+        let mut open: Option<HashMap<PathBuf, Vec<Holder>>> = None;
+
+        candidates
+            .into_iter()
+            .filter_map(|(path, kind)| {
+                match kind.stale(&path, &mut open) {
+                    Some(reason) => Some((path, kind, reason)),
+                    None => {
+                        debug!("{} is in use, keeping it", path.display());
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+fn collect(dir: &Path, depth: usize, min_age: Duration, out: &mut Vec<(PathBuf, Kind)>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else { continue };
+        let path = entry.path();
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            if depth > 1 {
+                collect(&path, depth - 1, min_age, out);
+            }
+            continue;
+        }
+        let young = metadata.modified().ok().and_then(|m| SystemTime::now().duration_since(m).ok()).is_none_or(|age| age < min_age);
+        if young {
+            continue;
+        }
+        let kind = if file_type.is_socket() {
+            Kind::Socket
+        } else if !file_type.is_file() {
+            continue;
+        } else if path.extension().is_some_and(|ext| ext == "pid") {
+            Kind::Pid
+        } else if path.extension().is_some_and(|ext| ext == "lock") || entry.file_name().to_string_lossy().starts_with("LCK..") {
+            Kind::Lock
+        } else {
+            continue;
+        };
+        out.push((path, kind));
+    }
+}
+
+/// The pid in a pid or lock file: decimal text, or (old uucp locks) a
+/// native-endian 4-byte integer.
+fn read_pid(path: &Path) -> Option<i32> {
+    let contents = fs::read(path).ok()?;
+    let text = String::from_utf8_lossy(&contents);
+    if let Some(pid) = text.split_whitespace().next().and_then(|word| word.parse::<i32>().ok()) {
+        return (pid > 0).then_some(pid);
+    }
+    let bytes: [u8; 4] = contents.as_slice().try_into().ok()?;
+    Some(i32::from_ne_bytes(bytes)).filter(|pid| *pid > 0)
+}
+
+/// Whether `pid` exists. EPERM means it does, just not ours.
+fn process_exists(pid: i32) -> bool {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+fn pid_file_stale(path: &Path) -> Option<String> {
+    let pid = read_pid(path)?;
+    (!process_exists(pid)).then(|| format!("pid {} is not running", pid))
+}
+
+fn lock_file_stale(path: &Path, open: &HashMap<PathBuf, Vec<Holder>>) -> Option<String> {
+    if let Some(pid) = read_pid(path) {
+        return (!process_exists(pid)).then(|| format!("lock holder pid {} is not running", pid));
+    }
+    let canonical = fs::canonicalize(path).ok()?;
+    if let Some(holder) = open.get(&canonical).and_then(|holders| holders.first()) {
+        debug!("{} is open in pid {} ({})", path.display(), holder.pid, holder.command);
+        return None;
+    }
+    (!is_locked(path)?).then(|| "no pid, not locked or open by any process".to_string())
+}
+
+/// Whether any process holds a flock or POSIX lock on `path`; `None` if
+/// that cannot be told.
+fn is_locked(path: &Path) -> Option<bool> {
+    let file = File::open(path).ok()?;
+    let fd = file.as_raw_fd();
+
+    let mut probe: libc::flock = unsafe { std::mem::zeroed() };
+    probe.l_type = libc::F_WRLCK as libc::c_short;
+    probe.l_whence = libc::SEEK_SET as libc::c_short;
+    if unsafe { libc::fcntl(fd, libc::F_GETLK, &mut probe) } != 0 {
+        return None;
+    }
+    if probe.l_type != libc::F_UNLCK as libc::c_short {
+        return Some(true);
+    }
+
+    if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return match io::Error::last_os_error().raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Some(true),
+            _ => None,
+        };
+    }
+    unsafe { libc::flock(fd, libc::LOCK_UN) };
+    Some(false)
+}
+
+fn socket_stale(path: &Path) -> Option<String> {
+    let refused = |e: &io::Error| e.raw_os_error() == Some(libc::ECONNREFUSED);
+    match UnixStream::connect(path) {
+        Ok(_) => None,
+        Err(e) if refused(&e) => Some("socket refuses connections".to_string()),
+        // A datagram socket: try it as one.
+        Err(e) if e.raw_os_error() == Some(libc::EPROTOTYPE) => {
+            let socket = UnixDatagram::unbound().ok()?;
+            match socket.connect(path) {
+                Err(e) if refused(&e) => Some("datagram socket has no listener".to_string()),
+                _ => None,
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+/// Removes (or, with `dry_run`, lists) the stale files under the task's
+/// dirs, recording why each one was stale.
+pub async fn clean_stale_files(net_mon: &NetMon, resume: &ResumeState, task: &StaleFilesTask, dry_run: bool) -> Result<()> {
+    info!("Looking for stale lock, pid and socket files...");
+    let scan = task.clone();
+    let stale = tokio::task::spawn_blocking(move || scan.find()).await?;
+    info!("{} stale files found", stale.len());
+
+    for (path, kind, reason) in stale {
+        let target = path.display().to_string();
+        if dry_run {
+            info!("Would remove {}: {}", target, reason);
+            net_mon.get_sender().send(Action::StalePlanned(target, reason)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+        // A process may have started using it since the scan.
+        let recheck = path.clone();
+        let Some(reason) = tokio::task::spawn_blocking(move || kind.stale(&recheck, &mut None)).await? else {
+            info!("{} is in use again, keeping it", target);
+            continue;
+        };
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {
+                info!("Removed {}: {}", target, reason);
+                net_mon.get_sender().send(Action::StaleRemoved(target, reason)).await?;
+            }
+            Err(e) => {
+                warn!("Failed to remove {}: {}", target, e);
+                net_mon.get_sender().send(Action::TargetFailed(target, format!("Failed to remove: {}", e))).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn pid_files_in_text_and_binary() {
+        let dir = std::env::temp_dir().join(format!("stale-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pid");
+        let binary = 4321i32.to_ne_bytes();
+        let cases: [(&[u8], Option<i32>); 7] = [
+            (b"1234\n", Some(1234)),
+            (b"  42 extra\n", Some(42)),
+            (b"0\n", None),
+            (b"-5\n", None),
+            (b"", None),
+            (b"garbage", None),
+            (&binary, Some(4321)),
+        ];
+        for (contents, expected) in cases {
+            fs::write(&path, contents).unwrap();
+            assert_eq!(read_pid(&path), expected, "{:?}", contents);
+        }
+        assert_eq!(read_pid(&dir.join("missing")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_socket_is_stale_once_nothing_listens() {
+        let dir = std::env::temp_dir().join(format!("stale-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert_eq!(Kind::Socket.stale(&path, &mut None), None);
+        drop(listener);
+        assert_eq!(Kind::Socket.stale(&path, &mut None).as_deref(), Some("socket refuses connections"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! End-of-run statistics, built from NetMon's counters so they stay exact
+//! even when older records were evicted from memory.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+use crate::net_mon::{ActionRecord, ActionStats, UserStats};
+use crate::session::Session;
+use crate::utils::format_bytes;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub session: Session,
+    pub elapsed_ms: u64,
+    pub actions: u64,
+    pub files_removed: u64,
+    pub bytes_freed: u64,
+    pub bytes_overwritten: u64,
+    pub bytes_planned: u64,
+    pub errors_by_kind: BTreeMap<String, u64>,
+    pub task_durations_ms: BTreeMap<String, u64>,
+    pub slowest_items: Vec<(String, u64)>,
+    /// Records the audit collector did not get.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub audit_dropped: u64,
+    /// Per-user totals of tasks that clean users' homes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub by_user: BTreeMap<String, UserStats>,
+}
+
+impl RunSummary {
+    pub fn new(session: Session, elapsed: Duration, stats: &ActionStats) -> Self {
+        Self {
+            session,
+            elapsed_ms: elapsed.as_millis() as u64,
+            actions: stats.total,
+            files_removed: stats.files_removed,
+            bytes_freed: stats.bytes_freed,
+            bytes_overwritten: stats.bytes_overwritten,
+            bytes_planned: stats.bytes_planned,
+            errors_by_kind: stats.errors_by_kind.clone(),
+            task_durations_ms: stats.task_durations_ms.clone(),
+            slowest_items: stats.slowest_items.clone(),
+            audit_dropped: stats.audit_dropped,
+            by_user: stats.by_user.clone(),
+        }
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors_by_kind.values().sum()
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Run summary for session {}", self.session.id)?;
+        writeln!(f, "{:-<60}", "")?;
+        writeln!(f, "{:<40}{:>20}", "Status", format!("{:?}", self.session.status))?;
+        writeln!(f, "{:<40}{:>20}", "Host", self.session.host.hostname)?;
+        writeln!(f, "{:<40}{:>20}", "Elapsed", format!("{} ms", self.elapsed_ms))?;
+        writeln!(f, "{:<40}{:>20}", "Actions recorded", self.actions)?;
+        writeln!(f, "{:<40}{:>20}", "Files removed", self.files_removed)?;
+        writeln!(f, "{:<40}{:>20}", "Bytes freed", format_bytes(self.bytes_freed))?;
+        writeln!(f, "{:<40}{:>20}", "Bytes overwritten", format_bytes(self.bytes_overwritten))?;
+        if self.session.dry_run {
+            writeln!(f, "{:<40}{:>20}", "Bytes reclaimable (dry run)", format_bytes(self.bytes_planned))?;
+        }
+        writeln!(f, "{:<40}{:>20}", "Errors", self.errors())?;
+        for (kind, count) in &self.errors_by_kind {
+            writeln!(f, "  {:<38}{:>20}", kind, count)?;
+        }
+        if self.audit_dropped > 0 {
+            writeln!(f, "{:<40}{:>20}", "Audit records dropped", self.audit_dropped)?;
+        }
+
+        if !self.task_durations_ms.is_empty() {
+            writeln!(f, "{:-<60}", "")?;
+            writeln!(f, "{:<40}{:>20}", "Task", "Duration")?;
+            for (task, ms) in &self.task_durations_ms {
+                writeln!(f, "{:<40}{:>20}", task, format!("{} ms", ms))?;
+            }
+        }
+
+        if !self.by_user.is_empty() {
+            writeln!(f, "{:-<60}", "")?;
+            writeln!(f, "{:<28}{:>8}{:>12}{:>12}", "User", "Files", "Freed", "Errors")?;
+            for (user, stats) in &self.by_user {
+                writeln!(f, "{:<28}{:>8}{:>12}{:>12}", user, stats.files_removed, format_bytes(stats.bytes_freed), stats.errors)?;
+            }
+        }
+
+        if !self.slowest_items.is_empty() {
+            writeln!(f, "{:-<60}", "")?;
+            writeln!(f, "{:<40}{:>20}", "Slowest items", "Duration")?;
+            for (item, ms) in &self.slowest_items {
+                writeln!(f, "{:<40}{:>20}", truncate(item, 39), format!("{} ms", ms))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// What a run did in one user's home, saved as
+/// `user_report_<user>_<session_id>.json` so it can be handed to that user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserReport {
+    pub session: Session,
+    pub user: String,
+    pub stats: UserStats,
+    pub actions: Vec<ActionRecord>,
+}
+
+impl UserReport {
+    /// `user`'s share of `summary`, with their actions from `records`.
+    pub fn new(summary: &RunSummary, user: &str, records: &[ActionRecord]) -> Self {
+        Self {
+            session: summary.session.clone(),
+            user: user.to_string(),
+            stats: summary.by_user.get(user).cloned().unwrap_or_default(),
+            actions: records.iter().filter(|r| r.user.as_deref() == Some(user)).cloned().collect(),
+        }
+    }
+}
+
+/// Keeps the tail of long paths, which is the part that tells them apart.
+fn truncate(s: &str, max: usize) -> String {
+    let count = s.chars().count();
+    if count <= max {
+        return s.to_string();
+    }
+    let tail: String = s.chars().skip(count - (max - 3)).collect();
+    format!("...{}", tail)
+}
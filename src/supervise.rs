@@ -0,0 +1,334 @@
+//! Keeps tasks from hanging or dying halfway: per-task timeouts, retries
+//! with backoff for transient IO errors, and cancellation on Ctrl-C or
+//! SIGTERM.
+//!
+//! Cancelling doesn't drop a task on the spot. Its token is set, and
+//! long-running loops (the secure overwrite) check `is_cancelled()` and
+//! stop at a point they can record; only if the task hasn't stopped within
+//! `CANCEL_GRACE` is it dropped.
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{error, info, warn};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Notify;
+
+use crate::net_mon::{Action, NetMon};
+use crate::plan::PlanTask;
+
+/// How long a cancelled task gets to stop on its own.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+tokio::task_local! {
+    static CURRENT_CANCEL: CancelToken;
+}
+
+/// A cancellation flag shared by a task and whoever may cancel it.
+/// Children are cancelled with their parent, not the other way around.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+#[derive(Debug, Default)]
+struct CancelInner {
+    cancelled: AtomicBool,
+    notify: Notify,
+    parent: Option<CancelToken>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn child(&self) -> Self {
+        CancelToken {
+            inner: Arc::new(CancelInner { parent: Some(self.clone()), ..CancelInner::default() }),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+            || self.inner.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+
+    /// Resolves once this token or an ancestor is cancelled.
+    pub fn cancelled(&self) -> BoxFuture<'_, ()> {
+        async move {
+            loop {
+                let notified = self.inner.notify.notified();
+                if self.is_cancelled() {
+                    return;
+                }
+                match &self.inner.parent {
+                    Some(parent) => tokio::select! {
+                        _ = notified => {}
+                        _ = parent.cancelled() => {}
+                    },
+                    None => notified.await,
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Whether the task this runs in has been asked to stop.
+pub fn is_cancelled() -> bool {
+    CURRENT_CANCEL.try_with(|token| token.is_cancelled()).unwrap_or(false)
+}
+
+/// Cancels `token` on the first Ctrl-C or SIGTERM. A second one exits
+/// on the spot, for a task or hook that doesn't stop.
+pub fn cancel_on_signal(token: &CancelToken) -> Result<()> {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    let token = token.clone();
+    tokio::spawn(async move {
+        loop {
+            let signal = tokio::select! {
+                _ = tokio::signal::ctrl_c() => "Interrupted",
+                _ = sigterm.recv() => "Terminated",
+            };
+            if token.is_cancelled() {
+                error!("{} again, exiting without waiting for tasks", signal);
+                std::process::exit(130);
+            }
+            warn!("{}, cancelling tasks (again to exit now)", signal);
+            token.cancel();
+        }
+    });
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// Delay before the first retry; doubles on each one after.
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30_000
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        let ms = self.backoff_ms.saturating_mul(1 << retry.min(20)).min(self.max_backoff_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+/// Whether an error is an IO error that may go away on its own: a busy
+/// device or file, an interrupted call, a timeout.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().filter_map(|cause| cause.downcast_ref::<io::Error>()).any(|e| {
+        matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+            || matches!(e.raw_os_error(), Some(libc::EBUSY | libc::EAGAIN | libc::ETXTBSY | libc::ENOLCK))
+    })
+}
+
+/// Runs a task under its timeout and retry policy, cancelled with
+/// `cancel`. `attempt` builds one run of the task.
+pub async fn supervise<F, Fut>(net_mon: &NetMon, task: &PlanTask, cancel: &CancelToken, mut attempt: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let token = cancel.child();
+    let attempts = task.retry.as_ref().map_or(1, |policy| policy.attempts.max(1));
+    let runs = async {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let result = attempt().await;
+            let error = match result {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            let Some(policy) = task.retry.as_ref().filter(|_| tries < attempts && is_transient(&error)) else {
+                return Err(error);
+            };
+            if token.is_cancelled() {
+                return Err(error);
+            }
+            let delay = policy.delay(tries - 1);
+            info!("Task {} failed ({:#}), retry {} of {} in {:?}", task.name, error, tries, attempts - 1, delay);
+            net_mon.get_sender().send(Action::Retrying(task.name.clone(), tries, delay.as_millis() as u64)).await?;
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = token.cancelled() => return Err(error),
+            }
+        }
+    };
+    let mut runs = std::pin::pin!(CURRENT_CANCEL.scope(token.clone(), runs));
+
+    let timeout = task.timeout_secs.map(Duration::from_secs);
+    let reason = tokio::select! {
+        result = &mut runs => return result,
+        _ = async { tokio::time::sleep(timeout.unwrap_or_default()).await }, if timeout.is_some() => {
+            format!("timed out after {}s", timeout.unwrap_or_default().as_secs())
+        }
+        _ = token.cancelled() => "interrupted".to_string(),
+    };
+
+    token.cancel();
+    if tokio::time::timeout(CANCEL_GRACE, &mut runs).await.is_err() {
+        warn!("Task {} did not stop within {:?}, dropping it", task.name, CANCEL_GRACE);
+    }
+    warn!("Task {} cancelled: {}", task.name, reason);
+    net_mon.get_sender().send(Action::Cancelled(task.name.clone(), reason.clone())).await?;
+    Err(anyhow!("Task {} cancelled: {}", task.name, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use tokio::time::Instant;
+    use crate::plan::TaskKind;
+
+    fn task(retry: Option<RetryPolicy>, timeout_secs: Option<u64>) -> PlanTask {
+        let mut task = PlanTask::new("t", TaskKind::ClearBashHistory);
+        task.retry = retry;
+        task.timeout_secs = timeout_secs;
+        task
+    }
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy { attempts, backoff_ms: 100, max_backoff_ms: 250 }
+    }
+
+    fn busy() -> anyhow::Error {
+        anyhow::Error::new(io::Error::from_raw_os_error(libc::EBUSY)).context("Failed to remove /tmp/x")
+    }
+
+    async fn actions(net_mon: NetMon) -> Vec<Action> {
+        net_mon.close().await;
+        net_mon.get_actions().await.into_iter().map(|record| record.action).collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy { attempts: 5, backoff_ms: 500, max_backoff_ms: 30_000 };
+        let delays: Vec<u64> = [0, 1, 2, 5, 6, 40].iter().map(|&retry| policy.delay(retry).as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1000, 2000, 16_000, 30_000, 30_000]);
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&busy()));
+        assert!(is_transient(&anyhow::Error::new(io::Error::from(io::ErrorKind::TimedOut))));
+        assert!(!is_transient(&anyhow::Error::new(io::Error::from(io::ErrorKind::NotFound)).context("outer")));
+        assert!(!is_transient(&anyhow!("not an IO error")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_errors_with_backoff() {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let tries = AtomicU32::new(0);
+        let started = Instant::now();
+        let result = supervise(&net_mon, &task(Some(policy(4)), None), &CancelToken::new(), || async {
+            if tries.fetch_add(1, Ordering::SeqCst) < 3 { Err(busy()) } else { Ok(()) }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(tries.load(Ordering::SeqCst), 4);
+        // 100, 200, then capped at 250.
+        assert_eq!(started.elapsed(), Duration::from_millis(550));
+        let retries: Vec<Action> = actions(net_mon).await;
+        assert!(matches!(retries.as_slice(), [
+            Action::Retrying(_, 1, 100),
+            Action::Retrying(_, 2, 200),
+            Action::Retrying(_, 3, 250),
+        ]), "{:?}", retries);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_permanent_errors_and_after_the_last_attempt() {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let tries = AtomicU32::new(0);
+        let result = supervise(&net_mon, &task(Some(policy(3)), None), &CancelToken::new(), || async {
+            tries.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(anyhow!("no such task"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(tries.load(Ordering::SeqCst), 1);
+
+        tries.store(0, Ordering::SeqCst);
+        let result = supervise(&net_mon, &task(Some(policy(3)), None), &CancelToken::new(), || async {
+            tries.fetch_add(1, Ordering::SeqCst);
+            Err::<(), _>(busy())
+        })
+        .await;
+        assert!(is_transient(&result.unwrap_err()));
+        assert_eq!(tries.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_cancels_then_drops_after_the_grace_period() {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let started = Instant::now();
+        let result = supervise(&net_mon, &task(None, Some(10)), &CancelToken::new(), std::future::pending).await;
+        assert_eq!(result.unwrap_err().to_string(), "Task t cancelled: timed out after 10s");
+        assert_eq!(started.elapsed(), Duration::from_secs(10) + CANCEL_GRACE);
+
+        // A task that checks its token stops without the grace period.
+        let started = Instant::now();
+        let result = supervise(&net_mon, &task(None, Some(10)), &CancelToken::new(), || async {
+            while !is_cancelled() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(anyhow!("stopped"))
+        })
+        .await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(11));
+        let cancelled: Vec<Action> = actions(net_mon).await;
+        assert!(matches!(cancelled.as_slice(), [Action::Cancelled(..), Action::Cancelled(..)]), "{:?}", cancelled);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_stops_the_backoff() {
+        let net_mon = NetMon::new();
+        net_mon.start_monitoring().await;
+        let cancel = CancelToken::new();
+        let long = RetryPolicy { attempts: 5, backoff_ms: 60_000, max_backoff_ms: 60_000 };
+        let started = Instant::now();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+        let result = supervise(&net_mon, &task(Some(long), None), &cancel.child(), || async { Err::<(), _>(busy()) }).await;
+        assert!(result.is_err());
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,421 @@
+//! The freedesktop.org trash (Trash spec 1.0): moving files into it instead
+//! of deleting them, and emptying it, all of it or only the items trashed
+//! more than a given number of days ago (by their `DeletionDate`).
+//!
+//! A trash dir holds the items under `files/` and, for each, a
+//! `info/<name>.trashinfo` with the original path and the deletion date.
+//! Files on the home mount go to the home trash; files on other mounts go
+//! to that mount's `.Trash/$uid` (if the admin set it up) or `.Trash-$uid`.
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
+use chrono::{Local, NaiveDateTime};
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::journal::{skip_completed, ResumeState};
+use crate::net_mon::{Action, NetMon};
+use crate::utils::{dir_size, remove_and_record};
+
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrashOp {
+    /// Move `paths` to the trash.
+    Move,
+    /// Remove items from the trash dirs.
+    Empty,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashTask {
+    pub op: TrashOp,
+    /// `move`: what to trash.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    /// `empty`: only remove items trashed this many days ago or earlier.
+    #[serde(default)]
+    pub older_than_days: Option<u64>,
+    /// `empty`: trash dirs to empty; defaults to the home trash.
+    #[serde(default)]
+    pub trash_dirs: Vec<PathBuf>,
+}
+
+/// A trash dir: `files/` and `info/` below `root`.
+#[derive(Debug, Clone)]
+pub struct TrashDir {
+    pub root: PathBuf,
+}
+
+/// One trashed item, from its `.trashinfo` (or a file without one).
+#[derive(Debug, Clone)]
+pub struct TrashItem {
+    pub file: PathBuf,
+    pub info: Option<PathBuf>,
+    /// Original path, as recorded (relative for a mount's trash).
+    pub original: Option<String>,
+    /// When it was trashed, in local time.
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+impl TrashItem {
+    /// Whether the item was trashed before `cutoff`. Without a cutoff
+    /// every item is; without a readable date, none is.
+    pub fn trashed_before(&self, cutoff: Option<NaiveDateTime>) -> bool {
+        match (cutoff, self.deleted_at) {
+            (None, _) => true,
+            (Some(cutoff), Some(deleted_at)) => deleted_at < cutoff,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Local time `days` ago, the cutoff for `TrashItem::trashed_before`.
+pub fn cutoff(days: Option<u64>) -> Option<NaiveDateTime> {
+    let days = days?;
+    let ago = chrono::Duration::from_std(Duration::from_secs(days * 86_400)).ok()?;
+    Local::now().naive_local().checked_sub_signed(ago)
+}
+
+impl TrashDir {
+    /// `$XDG_DATA_HOME/Trash`, by default `~/.local/share/Trash`.
+    pub fn home() -> Option<Self> {
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+        Some(TrashDir { root: data.join("Trash") })
+    }
+
+    /// The home trash dir of the user whose home is `home`.
+    pub fn in_home(home: &Path) -> Self {
+        TrashDir { root: home.join(".local/share/Trash") }
+    }
+
+    fn files(&self) -> PathBuf {
+        self.root.join("files")
+    }
+
+    fn info(&self) -> PathBuf {
+        self.root.join("info")
+    }
+
+    /// The trash dir a file at `path` belongs in, and whether it is a
+    /// mount's trash (whose info files hold paths relative to `topdir`).
+    fn for_path(path: &Path) -> Result<(Self, Option<PathBuf>)> {
+        let device = fs::symlink_metadata(path).with_context(|| format!("Failed to stat {}", path.display()))?.dev();
+        let home = TrashDir::home().context("No home trash: HOME is not set")?;
+        let home_device = home.root.ancestors().find_map(|dir| fs::metadata(dir).ok()).map(|m| m.dev());
+        if home_device == Some(device) {
+            return Ok((home, None));
+        }
+
+        let topdir = mount_top(path, device);
+        let uid = unsafe { libc::geteuid() };
+        // `$topdir/.Trash` counts only if it is a real sticky dir.
+        let shared = topdir.join(".Trash");
+        if fs::symlink_metadata(&shared).is_ok_and(|m| m.is_dir() && m.permissions().mode() & 0o1000 != 0) {
+            return Ok((TrashDir { root: shared.join(uid.to_string()) }, Some(topdir)));
+        }
+        Ok((TrashDir { root: topdir.join(format!(".Trash-{}", uid)) }, Some(topdir)))
+    }
+
+    /// Every item in the trash. Info files whose item is gone are listed
+    /// with a `file` that does not exist, so emptying removes them too.
+    pub fn items(&self) -> Vec<TrashItem> {
+        let mut items = Vec::new();
+        if let Ok(infos) = fs::read_dir(self.info()) {
+            for entry in infos.flatten() {
+                let info = entry.path();
+                let name = entry.file_name();
+                let Some(stem) = name.as_bytes().strip_suffix(b".trashinfo") else { continue };
+                let (original, deleted_at) = read_info(&info);
+                items.push(TrashItem {
+                    file: self.files().join(std::ffi::OsStr::from_bytes(stem)),
+                    info: Some(info),
+                    original,
+                    deleted_at,
+                });
+            }
+        }
+        // Files without an info file: the spec leaves them to be removed.
+        let with_info: HashSet<PathBuf> = items.iter().map(|item| item.file.clone()).collect();
+        if let Ok(files) = fs::read_dir(self.files()) {
+            for entry in files.flatten() {
+                let file = entry.path();
+                if !with_info.contains(&file) {
+                    items.push(TrashItem { file, info: None, original: None, deleted_at: None });
+                }
+            }
+        }
+        items.sort_by(|a, b| a.file.cmp(&b.file));
+        items
+    }
+
+    /// Moves `path` into this trash, recording `original` as its path.
+    /// Returns where it ended up.
+    fn put(&self, path: &Path, original: &Path) -> Result<PathBuf> {
+        for dir in [self.root.clone(), self.files(), self.info()] {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let name = path.file_name().with_context(|| format!("Cannot trash {}", path.display()))?;
+        let contents = format!(
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(original),
+            Local::now().format(DATE_FORMAT)
+        );
+
+        // The info file is created first, exclusively: it reserves the name.
+        for n in 1.. {
+            let mut candidate = OsString::from(name);
+            if n > 1 {
+                candidate.push(format!(".{}", n));
+            }
+            let mut info_name = candidate.clone();
+            info_name.push(".trashinfo");
+            let info = self.info().join(info_name);
+            let target = self.files().join(&candidate);
+            if target.exists() {
+                continue;
+            }
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&info) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to create {}", info.display())),
+            };
+            file.write_all(contents.as_bytes()).and_then(|_| file.sync_all())
+                .with_context(|| format!("Failed to write {}", info.display()))?;
+            if let Err(e) = fs::rename(path, &target) {
+                let _ = fs::remove_file(&info);
+                return Err(e).with_context(|| format!("Failed to move {} to {}", path.display(), target.display()));
+            }
+            return Ok(target);
+        }
+        unreachable!()
+    }
+}
+
+/// Moves `path` to the trash it belongs in. Blocking.
+pub fn move_to_trash(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path).with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let (trash, topdir) = TrashDir::for_path(&path)?;
+    if path.starts_with(&trash.root) || trash.root.starts_with(&path) {
+        bail!("{} is or holds the trash", path.display());
+    }
+    let original = match &topdir {
+        Some(topdir) => path.strip_prefix(topdir).unwrap_or(&path).to_path_buf(),
+        None => path.clone(),
+    };
+    trash.put(&path, &original)
+}
+
+/// The `.trashinfo` file of an item in a trash's `files` dir.
+pub fn info_path(file: &Path) -> Option<PathBuf> {
+    let files = file.parent()?;
+    if files.file_name()? != "files" {
+        return None;
+    }
+    let mut name = file.file_name()?.to_os_string();
+    name.push(".trashinfo");
+    Some(files.parent()?.join("info").join(name))
+}
+
+/// The top dir of the mount holding `path`, which is on `device`.
+fn mount_top(path: &Path, device: u64) -> PathBuf {
+    let mut top = path;
+    for dir in path.ancestors().skip(1) {
+        match fs::metadata(dir) {
+            Ok(metadata) if metadata.dev() == device => top = dir,
+            _ => break,
+        }
+    }
+    top.to_path_buf()
+}
+
+fn read_info(info: &Path) -> (Option<String>, Option<NaiveDateTime>) {
+    let Ok(contents) = fs::read_to_string(info) else { return (None, None) };
+    let mut in_section = false;
+    let (mut original, mut deleted_at) = (None, None);
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_section = line == "[Trash Info]";
+        } else if !in_section {
+            continue;
+        } else if let Some(value) = line.strip_prefix("Path=") {
+            original = Some(percent_decode(value));
+        } else if let Some(value) = line.strip_prefix("DeletionDate=") {
+            deleted_at = NaiveDateTime::parse_from_str(value, DATE_FORMAT).ok();
+        }
+    }
+    (original, deleted_at)
+}
+
+/// Escapes a path as the spec's `Path` key wants it (RFC 2396), leaving
+/// `/` and the unreserved characters.
+fn percent_encode(path: &Path) -> String {
+    let mut out = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.!~*'()".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Trashes or empties as `task.op` says; with `dry_run`, only sizes up.
+pub async fn clean_trash(net_mon: &NetMon, resume: &ResumeState, task: &TrashTask, dry_run: bool) -> Result<()> {
+    match task.op {
+        TrashOp::Move => move_paths(net_mon, resume, &task.paths, dry_run).await,
+        TrashOp::Empty => empty_trash(net_mon, resume, task, dry_run).await,
+    }
+}
+
+async fn move_paths(net_mon: &NetMon, resume: &ResumeState, paths: &[PathBuf], dry_run: bool) -> Result<()> {
+    info!("Moving {} paths to the trash...", paths.len());
+    for path in paths {
+        let target = path.display().to_string();
+        if dry_run {
+            let sized = path.clone();
+            let size = tokio::task::spawn_blocking(move || dir_size(&sized)).await?;
+            net_mon.get_sender().send(Action::Planned(target, size)).await?;
+            continue;
+        }
+        if skip_completed(net_mon, resume, &target).await? {
+            continue;
+        }
+        let moved = path.clone();
+        match tokio::task::spawn_blocking(move || move_to_trash(&moved)).await? {
+            Ok(trashed) => {
+                info!("Trashed {} as {}", target, trashed.display());
+                net_mon.get_sender().send(Action::Trashed(target, trashed.display().to_string())).await?;
+            }
+            Err(e) => {
+                warn!("Failed to trash {}: {:#}", target, e);
+                net_mon.get_sender().send(Action::TargetFailed(target, format!("{:#}", e))).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn empty_trash(net_mon: &NetMon, resume: &ResumeState, task: &TrashTask, dry_run: bool) -> Result<()> {
+    let dirs: Vec<TrashDir> = if task.trash_dirs.is_empty() {
+        TrashDir::home().into_iter().collect()
+    } else {
+        task.trash_dirs.iter().map(|root| TrashDir { root: root.clone() }).collect()
+    };
+    let cutoff = cutoff(task.older_than_days);
+
+    for dir in dirs {
+        info!("Emptying {}...", dir.root.display());
+        let scan = dir.clone();
+        let items = tokio::task::spawn_blocking(move || {
+            scan.items()
+                .into_iter()
+                .filter(|item| item.trashed_before(cutoff))
+                .map(|item| {
+                    let size = dir_size(&item.file);
+                    (item, size)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await?;
+
+        for (item, size) in items {
+            let target = item.file.display().to_string();
+            match &item.original {
+                Some(original) => info!("{}: trashed from {}", target, original),
+                None => info!("{}: no trash info", target),
+            }
+            if dry_run {
+                net_mon.get_sender().send(Action::Planned(target, size)).await?;
+                continue;
+            }
+            if skip_completed(net_mon, resume, &target).await? {
+                continue;
+            }
+            // An info file whose item is already gone is just removed.
+            let removed = tokio::fs::symlink_metadata(&item.file).await.is_err()
+                || remove_and_record(net_mon, &item.file, size).await?;
+            if let (true, Some(info)) = (removed, &item.info) {
+                if let Err(e) = tokio::fs::remove_file(info).await {
+                    warn!("Failed to remove {}: {}", info.display(), e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let path = Path::new("/home/a b/ünï%code/[x]#1.txt");
+        let encoded = percent_encode(path);
+        assert_eq!(encoded, "/home/a%20b/%C3%BCn%C3%AF%25code/%5Bx%5D%231.txt");
+        assert_eq!(percent_decode(&encoded), path.to_str().unwrap());
+        assert_eq!(percent_encode(Path::new("/a-b_c.d!~*'()")), "/a-b_c.d!~*'()");
+    }
+
+    #[test]
+    fn malformed_escapes_decode_as_is() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%41%42c"), "ABc");
+    }
+
+    #[test]
+    fn info_files() {
+        let dir = std::env::temp_dir().join(format!("trash-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let info = dir.join("a.trashinfo");
+        fs::write(&info, "[Trash Info]\nPath=/home/u/my%20file\nDeletionDate=2024-03-01T10:20:30\n").unwrap();
+        let (original, deleted_at) = read_info(&info);
+        assert_eq!(original.as_deref(), Some("/home/u/my file"));
+        assert_eq!(deleted_at, NaiveDateTime::parse_from_str("2024-03-01T10:20:30", DATE_FORMAT).ok());
+
+        // Keys outside the section and bad dates are ignored.
+        fs::write(&info, "Path=/outside\n[Other]\nPath=/other\n[Trash Info]\nDeletionDate=yesterday\n").unwrap();
+        assert_eq!(read_info(&info), (None, None));
+        assert_eq!(read_info(&dir.join("missing.trashinfo")), (None, None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,24 +1,35 @@
-use serde::Serialize;
-use std::fs;
-use std::path::Path;
-use chrono::Local;
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
 use anyhow::{Result, Context};
+use uuid::Uuid;
+
+use crate::net_mon::{Action, NetMon};
+
+const REPORTS_DIR: &str = "./safe/tmp/reports";
+const REPORT_INDEX: &str = "index.jsonl";
 
 /// Saves a vector of serializable data to a JSON file.
 ///
 /// # Arguments
 ///
-///  `data` - A vector of data that implements the Serialize trait
-///  `file_name` - The name of the file to save (without extension)
+///  `data` - Any data that implements the Serialize trait (a slice, a struct, ...)
+///  `file_name` - The name prefix of the file to save (without extension)
+///  `session_id` - The session the report belongs to; appended to the name
 ///
 /// # Returns
 ///
-///  `Result<(), anyhow::Error>` - Ok(()) if successful, or an error if something went wrong
+///  `Result<PathBuf, anyhow::Error>` - The written path if successful, or an error if something went wrong
 ///
 /// # Examples
 ///
 /// ```
 /// use your_crate::utils::save_to_json;
+/// use uuid::Uuid;
 ///
 /// #[derive(Serialize)]
 /// struct TestData {
@@ -31,31 +42,302 @@ use anyhow::{Result, Context};
 ///     TestData { id: 2, name: "Bob".to_string() },
 /// ];
 ///
-/// save_to_json(&data, "test_data").unwrap();
+/// save_to_json(&data, "test_data", Uuid::new_v4()).unwrap();
 /// ```
 /// 
-pub fn save_to_json<T: Serialize>(data: &[T], file_name: &str) -> Result<()> {
+pub fn save_to_json<T: Serialize + ?Sized>(data: &T, file_name: &str, session_id: Uuid) -> Result<PathBuf> {
+    // Serialize the data to JSON
+    let json = serde_json::to_string_pretty(data)
+        .with_context(|| "Failed to serialize data to JSON")?;
+
+    save_report(&json, "json", file_name, session_id)
+}
+
+/// Writes an already rendered report to
+/// `./safe/tmp/reports/<extension>/<file_name>_<session_id>.<extension>`
+/// and records it in the report index. The write is atomic, so readers see
+/// either the previous report or the complete new one. A resumed session
+/// keeps the reports of its earlier runs: its own get a `-resumed-<n>`
+/// suffix.
+pub fn save_report(contents: &str, extension: &str, file_name: &str, session_id: Uuid) -> Result<PathBuf> {
     // Create the directory if it doesn't exist
-    let dir_path = Path::new("./safe/tmp/reports/json");
-    fs::create_dir_all(dir_path)
+    let dir_path = Path::new(REPORTS_DIR).join(extension);
+    fs::create_dir_all(&dir_path)
         .with_context(|| format!("Failed to create directory: {}", dir_path.display()))?;
 
-    // Generate a timestamp for the file name
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-    let file_name = format!("{}_{}.json", file_name, timestamp);
+    let file_path = unused_report_path(&dir_path, file_name, session_id, extension);
 
-    // Construct the full file path
-    let file_path = dir_path.join(file_name);
+    write_atomic(&file_path, contents.as_bytes())?;
+    append_report_index(&ReportIndexEntry {
+        session_id,
+        name: file_name.to_string(),
+        format: extension.to_string(),
+        path: file_path.clone(),
+        written_at: Utc::now(),
+    })?;
 
-    // Serialize the data to JSON
-    let json = serde_json::to_string_pretty(data)
-        .with_context(|| "Failed to serialize data to JSON")?;
+    println!("Data saved to: {}", file_path.display());
 
-    // Write the JSON to the file
-    fs::write(&file_path, json)
-        .with_context(|| format!("Failed to write JSON to file: {}", file_path.display()))?;
+    Ok(file_path)
+}
 
-    println!("Data saved to: {}", file_path.display());
+/// The first of `<file_name>_<session_id>.<extension>`,
+/// `<file_name>_<session_id>-resumed-1.<extension>`, ... not in `dir`.
+fn unused_report_path(dir: &Path, file_name: &str, session_id: Uuid, extension: &str) -> PathBuf {
+    let path = dir.join(format!("{}_{}.{}", file_name, session_id, extension));
+    if !path.exists() {
+        return path;
+    }
+    (1..)
+        .map(|run| dir.join(format!("{}_{}-resumed-{}.{}", file_name, session_id, run, extension)))
+        .find(|path| !path.exists())
+        .expect("an unused run number")
+}
 
+/// Writes `contents` to a temp file next to `path`, syncs it and renames
+/// it over `path`.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Not a file path: {}", path.display()))?
+        .to_string_lossy();
+    let tmp_path = dir.join(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+
+    let result = (|| -> Result<()> {
+        let mut tmp = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create temp file: {}", tmp_path.display()))?;
+        tmp.write_all(contents)
+            .with_context(|| format!("Failed to write temp file: {}", tmp_path.display()))?;
+        tmp.sync_all().context("Failed to sync temp file")?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move report into place: {}", path.display()))?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result?;
+
+    // Persist the rename itself.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// One line of `./safe/tmp/reports/index.jsonl`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportIndexEntry {
+    pub session_id: Uuid,
+    pub name: String,
+    pub format: String,
+    pub path: PathBuf,
+    pub written_at: DateTime<Utc>,
+}
+
+/// Appends to the index. Entries are single short lines written with
+/// O_APPEND, so concurrent sessions don't clobber each other.
+fn append_report_index(entry: &ReportIndexEntry) -> Result<()> {
+    let index_path = Path::new(REPORTS_DIR).join(REPORT_INDEX);
+    let mut line = serde_json::to_string(entry).context("Failed to serialize index entry")?;
+    line.push('\n');
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&index_path)
+        .with_context(|| format!("Failed to open report index: {}", index_path.display()))?;
+    index.write_all(line.as_bytes())
+        .with_context(|| format!("Failed to update report index: {}", index_path.display()))?;
+    Ok(())
+}
+
+/// Reads every entry of the report index, oldest first.
+pub fn read_report_index() -> Result<Vec<ReportIndexEntry>> {
+    let index_path = Path::new(REPORTS_DIR).join(REPORT_INDEX);
+    let file = match File::open(&index_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open report index: {}", index_path.display())),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.context("Failed to read report index")?;
+        if let Ok(entry) = serde_json::from_str(&line) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Total size in bytes of a file or directory tree. Symlinks are counted
+/// as themselves, not followed. Blocking; call from `spawn_blocking`.
+pub fn dir_size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|entry| dir_size(&entry.path())).sum())
+        .unwrap_or(0)
+}
+
+/// Size in bytes and newest modification time of a file or directory
+/// tree, in one walk. Symlinks are not followed. Blocking.
+pub fn tree_stats(path: &Path) -> (u64, Option<SystemTime>) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return (0, None),
+    };
+    let mut size = if metadata.is_dir() { 0 } else { metadata.len() };
+    let mut newest = metadata.modified().ok();
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            let (entry_size, entry_newest) = tree_stats(&entry.path());
+            size += entry_size;
+            newest = newest.max(entry_newest);
+        }
+    }
+    (size, newest)
+}
+
+/// What `remove_tree_except` removed, with the bytes each entry held, and
+/// what it failed to remove, with why.
+#[derive(Debug, Default)]
+pub struct RemovedTree {
+    pub removed: Vec<(PathBuf, u64)>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Removes everything at or below `path` except the paths in `keep` and
+/// the directories leading to them. Whole subtrees without kept paths are
+/// removed, and listed, as one entry. Blocking.
+pub fn remove_tree_except(path: &Path, keep: &HashSet<PathBuf>) -> RemovedTree {
+    let mut tree = RemovedTree::default();
+    remove_below(path, keep, &mut tree);
+    tree
+}
+
+fn remove_below(path: &Path, keep: &HashSet<PathBuf>, tree: &mut RemovedTree) {
+    if keep.contains(path) {
+        return;
+    }
+    let Ok(metadata) = fs::symlink_metadata(path) else { return };
+    let (size, removed) = if !metadata.is_dir() {
+        (metadata.len(), fs::remove_file(path))
+    } else if !keep.iter().any(|kept| kept.starts_with(path)) {
+        (dir_size(path), fs::remove_dir_all(path))
+    } else {
+        for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+            remove_below(&entry.path(), keep, tree);
+        }
+        return;
+    };
+    match removed {
+        Ok(()) => tree.removed.push((path.to_path_buf(), size)),
+        Err(e) => {
+            log::warn!("Error removing {}: {}", path.display(), e);
+            tree.failed.push((path.to_path_buf(), format!("Failed to remove: {}", e)));
+        }
+    }
+}
+
+/// Removes a file, symlink or directory tree and records the outcome:
+/// `FileDeleted`, `SpaceFreed` and `ItemTimed` on success, `TargetFailed`
+/// otherwise. Returns whether the path was removed.
+pub async fn remove_and_record(net_mon: &NetMon, path: &Path, size: u64) -> Result<bool> {
+    let target = path.display().to_string();
+    let started = std::time::Instant::now();
+    let is_dir = tokio::fs::symlink_metadata(path).await.map(|m| m.is_dir()).unwrap_or(false);
+    let removed = if is_dir {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    };
+    match removed {
+        Ok(()) => {
+            net_mon.get_sender().send(Action::FileDeleted(target.clone())).await?;
+            net_mon.get_sender().send(Action::SpaceFreed(target.clone(), size)).await?;
+            net_mon.get_sender().send(Action::ItemTimed(target, started.elapsed().as_millis() as u64)).await?;
+            Ok(true)
+        }
+        Err(e) => {
+            log::error!("Error removing {}: {}", target, e);
+            net_mon.get_sender().send(Action::TargetFailed(target, format!("Failed to remove: {}", e))).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumed_runs_get_their_own_report() {
+        let dir = std::env::temp_dir().join(format!("reports-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let session_id = Uuid::new_v4();
+        let mut paths = Vec::new();
+        for _ in 0..3 {
+            let path = unused_report_path(&dir, "cleanup_actions", session_id, "json");
+            write_atomic(&path, b"{}").unwrap();
+            paths.push(path);
+        }
+        let names: Vec<String> = paths.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, [
+            format!("cleanup_actions_{}.json", session_id),
+            format!("cleanup_actions_{}-resumed-1.json", session_id),
+            format!("cleanup_actions_{}-resumed-2.json", session_id),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_tree_except_lists_what_it_removed() {
+        let dir = std::env::temp_dir().join(format!("remove-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("held/sub")).unwrap();
+        fs::create_dir_all(dir.join("gone/sub")).unwrap();
+        fs::write(dir.join("held/open.log"), b"open").unwrap();
+        fs::write(dir.join("held/closed.log"), b"closed").unwrap();
+        fs::write(dir.join("gone/sub/a"), b"aaaa").unwrap();
+        fs::write(dir.join("top"), b"t").unwrap();
+        let keep: HashSet<PathBuf> = [dir.join("held/open.log")].into_iter().collect();
+
+        let tree = remove_tree_except(&dir, &keep);
+        let mut removed: Vec<(String, u64)> = tree
+            .removed
+            .iter()
+            .map(|(path, size)| (path.strip_prefix(&dir).unwrap().display().to_string(), *size))
+            .collect();
+        removed.sort();
+        assert_eq!(removed, [
+            ("gone".to_string(), 4),
+            ("held/closed.log".to_string(), 6),
+            ("held/sub".to_string(), 0),
+            ("top".to_string(), 1),
+        ]);
+        assert!(tree.failed.is_empty());
+        assert!(dir.join("held/open.log").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}